@@ -12,9 +12,9 @@ use crate::{
     github::{
         GitHub, PullRequest, PullRequestRequestReviewers, PullRequestState, PullRequestUpdate,
     },
-    message::{validate_commit_message, MessageSection},
+    message::{validate_commit_message, MessageSection, MessageSectionsMap},
     output::{output, write_commit_title},
-    utils::{parse_name_list, remove_all_parens, run_command},
+    utils::{parse_name_list, remove_all_parens},
 };
 use git2::Oid;
 use indoc::{formatdoc, indoc};
@@ -44,6 +44,12 @@ pub struct DiffOptions {
     #[clap(long)]
     cherry_pick: bool,
 
+    /// If --cherry-pick produces conflicts, push the commit anyway with
+    /// conflict markers left in the files for the author to resolve in the
+    /// Pull Request, instead of aborting.
+    #[clap(long)]
+    allow_conflicts: bool,
+
     /// Base revision for --all mode (if not specified, uses trunk)
     #[clap(long)]
     base: Option<String>,
@@ -52,28 +58,88 @@ pub struct DiffOptions {
     /// If a range is provided, behaves like --all mode. If not specified, uses '@-'
     #[clap(short = 'r', long)]
     revision: Option<String>,
+
+    /// Submit every commit matched by an arbitrary jj revset expression, e.g.
+    /// 'mine() & ~::main' or 'trunk()..@'. Unlike --revision/--base, this is
+    /// passed straight to `jj log -r` rather than being interpreted as a
+    /// linear from/to pair, so it covers selections that aren't a single
+    /// contiguous range of the current stack.
+    #[clap(long, conflicts_with_all = ["all", "revision", "base"])]
+    revset: Option<String>,
+
+    /// In multi-commit mode, make each PR target the PR branch of the
+    /// commit directly below it in the stack, instead of spr's usual
+    /// synthetic `[spr] changes ...` base branch. This gives the GitHub
+    /// "stacked PR" experience: each PR shows only its own diff, and only
+    /// the bottom PR ever needs a master merge.
+    #[clap(long)]
+    stack: bool,
+
+    /// Compute what would be pushed for every commit - cherry-picks, base
+    /// branch derivation, the resulting PR commit - and print it as a plan,
+    /// but stop before any `git push` or any change on GitHub. Existing Pull
+    /// Requests and reviewers are still looked up through the GitHub API.
+    /// Lets you preview a large or risky stack before it actually changes
+    /// anything on GitHub.
+    #[clap(long)]
+    dry_run: bool,
+
+    /// Push PR branches even if the remote branch has moved since spr last
+    /// pushed to it. Without this flag, `diff` refuses to overwrite a PR
+    /// branch or base branch that someone else (or another clone) has
+    /// pushed to since our last push, the same way `git push
+    /// --force-with-lease` protects a plain `git push --force`.
+    #[clap(long)]
+    force: bool,
+
+    /// When a new Pull Request has no explicit `Reviewers:` field, auto-fill
+    /// it from the repo's CODEOWNERS file, the same way GitHub's own UI
+    /// assigns reviewers. Off by default, since not every repo wants every
+    /// PR to auto-request its owners.
+    #[clap(long)]
+    owners_reviewers: bool,
 }
 
-pub async fn diff(
+pub async fn diff<G: crate::github::GitHubApi>(
     opts: DiffOptions,
     jj: &crate::jj::Jujutsu,
-    gh: &mut crate::github::GitHub,
+    gh: &mut G,
     config: &crate::config::Config,
 ) -> Result<()> {
     // Abort right here if the local Jujutsu repository is not clean
     jj.check_no_uncommitted_changes()?;
 
+    // Treat the whole submit as one atomic jj operation: if we return early
+    // or bail out with `?` anywhere below, this guard's `Drop` rolls back
+    // any local jj state (e.g. a batch of `jj describe` calls) automatically,
+    // leaving behind no more than a `jj op undo` away from where the user
+    // started. Only pushed-to-GitHub state is left as is - there's no way
+    // to roll that back from here.
+    let operation_guard = jj.begin_atomic_submit()?;
+
     let mut result = Ok(());
 
+    // A --revset selection is, like --all/a range, potentially more than one
+    // commit. Unlike range mode, though, it has no --base expression to
+    // resolve, so the parent of the (topologically) first matched commit is
+    // taken as the stack's master base.
+    let use_revset_mode = opts.revset.is_some();
+
     // Determine revision and whether to use range mode
-    let (use_range_mode, base_rev, target_rev) = crate::revision_utils::parse_revision_and_range(
-        opts.revision.as_deref(),
-        opts.all,
-        opts.base.as_deref(),
-    )?;
+    let (use_range_mode, base_rev, target_rev) = if use_revset_mode {
+        (false, String::new(), String::new())
+    } else {
+        crate::revision_utils::parse_revision_and_range(
+            opts.revision.as_deref(),
+            opts.all,
+            opts.base.as_deref(),
+        )?
+    };
 
     // Get commits to process
-    let mut prepared_commits = if use_range_mode {
+    let mut prepared_commits = if let Some(revset) = opts.revset.as_deref() {
+        jj.get_prepared_commits_for_revset(config, revset)?
+    } else if use_range_mode {
         // Get range of commits from base to target
         jj.get_prepared_commits_from_to(config, &base_rev, &target_rev)?
     } else {
@@ -84,7 +150,18 @@ pub async fn diff(
     // Determine the master base OID - this is the commit on master that the stack is based on
     let master_base_oid = if let Some(first_commit) = prepared_commits.first() {
         if use_range_mode {
-            // For range mode, the parent of the first commit is the master base
+            // `base_rev` may be an arbitrary revset expression (`@-`,
+            // `description(glob:"WIP*")`, `base-1`, ...), not necessarily a
+            // branch name or a commit with exactly one child, so resolve it
+            // through jj's revset engine ourselves rather than assuming the
+            // first matched commit's parent is the base. This also gives a
+            // clear error if --base names zero or more than one commit,
+            // instead of silently picking a parent that may not be it.
+            jj.resolve_single_revision(&base_rev)?
+        } else if use_revset_mode {
+            // For an arbitrary --revset selection there's no single --base
+            // expression to resolve, so fall back to the parent of the
+            // (topologically) first matched commit.
             first_commit.parent_oid
         } else {
             // For single commit mode, find the actual merge base with master
@@ -92,9 +169,21 @@ pub async fn diff(
         }
     } else {
         output("👋", "No commits found - nothing to do. Good bye!")?;
+        operation_guard.disarm();
         return result;
     };
 
+    // The branch every PR in this submit ultimately needs to land on -
+    // i.e. the user's actual integration target, as opposed to whatever
+    // branch spr hands GitHub as a given PR's immediate base (its own
+    // synthesized `spr/<user>/...` branch, or the previous PR's branch in
+    // `--stack` mode). This is the same for every commit in the stack, so
+    // it's resolved once here rather than per-PR.
+    let trusted_target_branch_name = opts
+        .base
+        .clone()
+        .unwrap_or_else(|| config.master_ref.branch_name().to_string());
+
     #[allow(clippy::needless_collect)]
     let pull_request_tasks: Vec<_> = prepared_commits
         .iter()
@@ -106,6 +195,12 @@ pub async fn diff(
 
     let mut message_on_prompt = "".to_string();
 
+    // In --stack mode, the branch of the commit most recently processed,
+    // together with its oid, so the next commit can tell whether it is
+    // directly stacked on top of it and, if so, target that branch as its
+    // own PR base instead of a synthesized one.
+    let mut previous_stack_commit: Option<(Oid, crate::github::GitHubBranch)> = None;
+
     for (prepared_commit, pull_request_task) in
         zip(prepared_commits.iter_mut(), pull_request_tasks.into_iter())
     {
@@ -121,11 +216,20 @@ pub async fn diff(
 
         write_commit_title(prepared_commit)?;
 
+        let stacked_base = previous_stack_commit.as_ref().and_then(|(oid, branch)| {
+            if opts.stack && *oid == prepared_commit.parent_oid {
+                Some(branch.clone())
+            } else {
+                None
+            }
+        });
+        let commit_oid = prepared_commit.oid;
+
         // The further implementation of the diff command is in a separate function.
         // This makes it easier to run the code to update the local commit message
         // with all the changes that the implementation makes at the end, even if
         // the implementation encounters an error or exits early.
-        result = diff_impl(
+        match diff_impl(
             &opts,
             &mut message_on_prompt,
             jj,
@@ -133,9 +237,15 @@ pub async fn diff(
             config,
             prepared_commit,
             master_base_oid,
+            &trusted_target_branch_name,
             pull_request,
+            stacked_base,
         )
-        .await;
+        .await
+        {
+            Ok(branch) => previous_stack_commit = Some((commit_oid, branch)),
+            Err(err) => result = Err(err),
+        }
     }
 
     // This updates the commit message in the local Jujutsu repository (if it was
@@ -145,26 +255,40 @@ pub async fn diff(
         jj.rewrite_commit_messages(prepared_commits.as_mut_slice()),
     );
 
+    if result.is_ok() {
+        operation_guard.disarm();
+    }
+
     result
 }
 
 #[allow(clippy::too_many_arguments)]
-async fn diff_impl(
+async fn diff_impl<G: crate::github::GitHubApi>(
     opts: &DiffOptions,
     message_on_prompt: &mut String,
     jj: &crate::jj::Jujutsu,
-    gh: &mut crate::github::GitHub,
+    gh: &mut G,
     config: &crate::config::Config,
     local_commit: &mut crate::jj::PreparedCommit,
     master_base_oid: Oid,
+    trusted_target_branch_name: &str,
     pull_request: Option<PullRequest>,
-) -> Result<()> {
+    // In --stack mode, the PR branch of the commit directly below this one
+    // in the stack, if there is one. When set, this commit's PR targets
+    // that branch directly instead of a synthesized base branch.
+    stacked_base: Option<crate::github::GitHubBranch>,
+) -> Result<crate::github::GitHubBranch> {
     // Parsed commit message of the local commit
     let message = &mut local_commit.message;
 
     // Check if the local commit is based directly on the master branch.
     let directly_based_on_master = local_commit.parent_oid == master_base_oid;
 
+    // Paths left with conflict markers by a --cherry-pick under
+    // --allow-conflicts, if any. Reported to the user below and noted in the
+    // commit pushed to GitHub, so the author knows to resolve them there.
+    let mut conflicted_paths: Vec<String> = Vec::new();
+
     // Determine the trees the Pull Request branch and the base branch should
     // have when we're done here.
     let (new_head_tree, new_base_tree) = if !opts.cherry_pick || directly_based_on_master {
@@ -184,12 +308,31 @@ async fn diff_impl(
         // Cherry-pick the current commit onto master
         let index = jj.cherrypick(local_commit.oid, master_base_oid)?;
 
-        if index.has_conflicts() {
-            return Err(Error::new(formatdoc!(
-                "This commit cannot be cherry-picked on {master}.",
-                master = config.master_ref.branch_name(),
-            )));
-        }
+        let index = if index.has_conflicts() {
+            if !opts.allow_conflicts {
+                return Err(Error::new(formatdoc!(
+                    "This commit cannot be cherry-picked on {master}.",
+                    master = config.master_ref.branch_name(),
+                )));
+            }
+
+            let (index, paths) = jj.resolve_conflicts_with_markers(index)?;
+            conflicted_paths = paths;
+
+            output(
+                "⚠️",
+                &format!(
+                    "Cherry-pick onto {} has conflicts in: {} - pushing anyway with \
+                     conflict markers for the author to resolve in the Pull Request.",
+                    config.master_ref.branch_name(),
+                    conflicted_paths.join(", "),
+                ),
+            )?;
+
+            index
+        } else {
+            index
+        };
 
         // This is the tree we are getting from cherrypicking the local commit
         // on master.
@@ -288,6 +431,48 @@ async fn diff_impl(
 
             message.insert(MessageSection::Reviewers, checked_reviewers.join(", "));
             local_commit.message_changed = true;
+        } else if let Some(workdir) = opts
+            .owners_reviewers
+            .then(|| jj.git_repo.workdir())
+            .flatten()
+        {
+            // No explicit `Reviewers:` field - fall back to CODEOWNERS, the
+            // same way GitHub's own UI auto-assigns reviewers for a PR. Only
+            // when the user opted in with --owners-reviewers.
+            let changed_paths = jj.get_changed_paths_for_commit(local_commit.oid)?;
+            let owners = codeowners_for_paths(workdir, &changed_paths);
+
+            for owner in &owners {
+                let name = owner.trim_start_matches('@');
+                if let Some((_org, team)) = name.split_once('/') {
+                    // Resolve through GitHub just like an explicit
+                    // `Reviewers:` team, so a stale/mistyped CODEOWNERS
+                    // entry doesn't turn into an invalid
+                    // `request_reviewers` call. Unlike the explicit field
+                    // (which the user typed and should get feedback on), an
+                    // unresolvable auto-derived owner is silently skipped
+                    // rather than aborting the whole submit.
+                    if let Ok(team) =
+                        GitHub::get_github_team((&config.owner).into(), team.into()).await
+                    {
+                        requested_reviewers
+                            .team_reviewers
+                            .push(team.slug.to_string());
+                    }
+                } else if name.contains('@') {
+                    // CODEOWNERS also allows bare email addresses - spr has
+                    // no way to turn one of those into a GitHub login, so
+                    // skip it rather than guess.
+                    continue;
+                } else if let Ok(user) = GitHub::get_github_user(name.to_string()).await {
+                    requested_reviewers.reviewers.push(user.login);
+                }
+            }
+
+            if !owners.is_empty() {
+                message.insert(MessageSection::Reviewers, owners.join(", "));
+                local_commit.message_changed = true;
+            }
         }
     }
 
@@ -316,11 +501,10 @@ async fn diff_impl(
             let pr_head_tree = jj.get_tree_oid_for_commit(pr.head_oid)?;
 
             let current_master_oid = jj.resolve_reference(config.master_ref.local())?;
-            // Use git for merge base calculation since jj doesn't expose this directly
-            let pr_base_oid = jj.git_repo.merge_base(pr.head_oid, pr.base_oid)?;
+            let pr_base_oid = jj.merge_base(pr.head_oid, pr.base_oid)?;
             let pr_base_tree = jj.get_tree_oid_for_commit(pr_base_oid)?;
 
-            let pr_master_base = jj.git_repo.merge_base(pr.head_oid, current_master_oid)?;
+            let pr_master_base = jj.merge_base(pr.head_oid, current_master_oid)?;
 
             (
                 pr.head_oid,
@@ -365,7 +549,7 @@ async fn diff_impl(
                 }
             }
 
-            return Ok(());
+            return Ok(pull_request_branch);
         }
     }
 
@@ -421,7 +605,20 @@ async fn diff_impl(
     // commit is not directly based on master, we have to create this new PR
     // with a base branch, so that is case 3.
 
-    let (pr_base_parent, base_branch) = if pr_base_tree == new_base_tree && !needs_merging_master {
+    // `base_branch_parents` records the parents of a freshly-derived base
+    // branch commit (Case 3 below), purely so --dry-run has something to
+    // report; every other case leaves it empty.
+    let mut base_branch_parents: Vec<Oid> = Vec::new();
+
+    let (pr_base_parent, base_branch) = if let Some(stacked_base) = stacked_base {
+        // --stack mode, and this commit sits directly on top of the one we
+        // just created/updated a PR for: target that PR's branch as our
+        // base instead of synthesizing one. The dependency between the two
+        // PRs is expressed entirely through this base, so there's nothing
+        // to merge into our branch here - only the bottom-of-stack commit
+        // (which has no stacked_base) ever needs a master merge.
+        (None, Some(stacked_base))
+    } else if pr_base_tree == new_base_tree && !needs_merging_master {
         // Case 1
         (None, base_branch)
     } else if base_branch.is_none() && (directly_based_on_master || opts.cherry_pick) {
@@ -442,6 +639,7 @@ async fn diff_impl(
         if needs_merging_master && pr_base_oid != master_base_oid {
             parents.push(master_base_oid);
         }
+        base_branch_parents = parents.clone();
 
         let new_base_branch_commit = jj.create_derived_commit(
             local_commit.parent_oid,
@@ -472,8 +670,15 @@ async fn diff_impl(
         (Some(new_base_branch_commit), Some(base_branch))
     };
 
+    // `base_branch` here is whatever GitHub sees as this PR's immediate
+    // base - spr's own synthesized base branch, or the previous PR's branch
+    // in `--stack` mode, neither of which is something the user chose. What
+    // we actually want to validate is where the whole stack ultimately
+    // lands, so check that instead.
+    check_trusted_base_branch(config, trusted_target_branch_name)?;
+
     let mut github_commit_message = opts.message.clone();
-    if pull_request.is_some() && github_commit_message.is_none() {
+    if pull_request.is_some() && github_commit_message.is_none() && !opts.dry_run {
         let input = {
             let message_on_prompt = message_on_prompt.clone();
 
@@ -511,27 +716,88 @@ async fn diff_impl(
     }
 
     // Create the new commit
+    let conflict_note = if conflicted_paths.is_empty() {
+        String::new()
+    } else {
+        format!(
+            "\n\n[spr] Cherry-pick had conflicts in: {} - resolve the \
+             conflict markers before merging.",
+            conflicted_paths.join(", "),
+        )
+    };
     let pr_commit = jj.create_derived_commit(
         local_commit.oid,
         &format!(
-            "{}\n\nCreated using jj-spr {}",
+            "{}\n\nCreated using jj-spr {}{}",
             github_commit_message
                 .as_ref()
                 .map(|s| &s[..])
                 .unwrap_or("[jj-spr] initial version"),
             env!("CARGO_PKG_VERSION"),
+            conflict_note,
         ),
         new_head_tree,
         &pr_commit_parents[..],
     )?;
 
-    let mut cmd = tokio::process::Command::new("git");
-    cmd.arg("push")
-        .arg("--atomic")
-        .arg("--no-verify")
-        .arg("--")
-        .arg(&config.remote_name)
-        .arg(format!("{}:{}", pr_commit, pull_request_branch.on_github()));
+    if opts.dry_run {
+        let base_branch_name = base_branch
+            .as_ref()
+            .map(|b| b.branch_name())
+            .unwrap_or_else(|| config.master_ref.branch_name());
+
+        print_dry_run_plan(DryRunPlan {
+            short_id: &local_commit.short_id,
+            is_new_pull_request: pull_request.is_none(),
+            pull_request_branch: &pull_request_branch,
+            pr_head_oid,
+            new_pr_head_oid: pr_commit,
+            base_branch_name,
+            base_branch_parents: &base_branch_parents,
+            needs_merging_master,
+        })?;
+
+        return Ok(pull_request_branch);
+    }
+
+    // The bookmarks we set below track the user's own changes, not the
+    // synthetic derived commits (`pr_commit`/`base_branch_commit`) we push
+    // to GitHub - those only exist to give GitHub something to diff/merge
+    // and would otherwise leave `jj log` showing bookmarks parked on
+    // detached, throwaway commits instead of on the user's stack.
+    let local_change_id = jj.get_change_id_for_commit(local_commit.oid)?;
+    let local_parent_change_id = jj.get_change_id_for_commit(local_commit.parent_oid)?;
+
+    // Before pushing, make sure nobody else has moved these branches since we
+    // last observed them (e.g. another machine submitting the same stack).
+    // This is force-with-lease semantics: refuse to clobber a remote branch
+    // that has moved out from under us.
+    if pull_request.is_some() {
+        resolve_remote_divergence(
+            jj,
+            &config.remote_name,
+            pull_request_branch.on_github(),
+            pr_head_oid,
+            pr_commit,
+            opts.force,
+        )
+        .await?;
+        if let Some(ref base_branch) = base_branch {
+            if pr_base_parent.is_none() {
+                resolve_remote_divergence(
+                    jj,
+                    &config.remote_name,
+                    base_branch.on_github(),
+                    pr_base_oid,
+                    pr_base_oid,
+                    opts.force,
+                )
+                .await?;
+            }
+        }
+    }
+
+    let mut refspecs = vec![format!("{}:{}", pr_commit, pull_request_branch.on_github())];
 
     if let Some(pull_request) = pull_request {
         // We are updating an existing Pull Request
@@ -561,24 +827,37 @@ async fn diff_impl(
             pull_request_updates.update_message(&pull_request, message);
         }
 
+        // Protect the Pull Request branch (and the base branch, if we're
+        // also pushing a new commit for it) with git's own force-with-
+        // lease, on top of the three-way check already done above - this
+        // way the remote rejects the push atomically if the branch moved
+        // again in the narrow window between that check and this push.
+        let mut leased_refs = vec![(pull_request_branch.on_github().to_string(), pr_head_oid)];
+
         if let Some(base_branch) = base_branch {
             // We are using a base branch.
 
             if let Some(base_branch_commit) = pr_base_parent {
                 // ...and we prepared a new commit for it, so we need to push an
                 // update of the base branch.
-                cmd.arg(format!(
+                refspecs.push(format!(
                     "{}:{}",
                     base_branch_commit,
                     base_branch.on_github()
                 ));
+                leased_refs.push((base_branch.on_github().to_string(), pr_base_oid));
             }
 
             // Push the new commit onto the Pull Request branch (and also the
-            // new base commit, if we added that to cmd above).
-            run_command(&mut cmd)
-                .await
-                .reword("git push failed".to_string())?;
+            // new base commit, if we added that to refspecs above).
+            jj.push_with_lease(&config.remote_name, &refspecs, &leased_refs, opts.force)?;
+
+            jj.set_bookmark(pull_request_branch.branch_name(), &local_change_id)?;
+            jj.record_push_lease(pull_request_branch.on_github(), pr_commit)?;
+            if let Some(base_branch_commit) = pr_base_parent {
+                jj.set_bookmark(base_branch.branch_name(), &local_parent_change_id)?;
+                jj.record_push_lease(base_branch.on_github(), base_branch_commit)?;
+            }
 
             // If the Pull Request's base is not set to the base branch yet,
             // change that now.
@@ -588,9 +867,10 @@ async fn diff_impl(
         } else {
             // The Pull Request is against the master branch. In that case we
             // only need to push the update to the Pull Request branch.
-            run_command(&mut cmd)
-                .await
-                .reword("git push failed".to_string())?;
+            jj.push_with_lease(&config.remote_name, &refspecs, &leased_refs, opts.force)?;
+
+            jj.set_bookmark(pull_request_branch.branch_name(), &local_change_id)?;
+            jj.record_push_lease(pull_request_branch.on_github(), pr_commit)?;
         }
 
         if !pull_request_updates.is_empty() {
@@ -602,16 +882,23 @@ async fn diff_impl(
 
         // If there's a base branch, add it to the push
         if let (Some(base_branch), Some(base_branch_commit)) = (&base_branch, pr_base_parent) {
-            cmd.arg(format!(
+            refspecs.push(format!(
                 "{}:{}",
                 base_branch_commit,
                 base_branch.on_github()
             ));
         }
-        // Push the pull request branch and the base branch if present
-        run_command(&mut cmd)
-            .await
-            .reword("git push failed".to_string())?;
+        // Push the pull request branch and the base branch if present. Both
+        // are brand new remote branches, so there's nothing to protect with
+        // force-with-lease here.
+        jj.push_with_lease(&config.remote_name, &refspecs, &[], opts.force)?;
+
+        jj.set_bookmark(pull_request_branch.branch_name(), &local_change_id)?;
+        jj.record_push_lease(pull_request_branch.on_github(), pr_commit)?;
+        if let (Some(base_branch), Some(base_branch_commit)) = (&base_branch, pr_base_parent) {
+            jj.set_bookmark(base_branch.branch_name(), &local_parent_change_id)?;
+            jj.record_push_lease(base_branch.on_github(), base_branch_commit)?;
+        }
 
         // Then call GitHub to create the Pull Request.
         let pull_request_number = gh
@@ -640,6 +927,17 @@ async fn diff_impl(
         message.insert(MessageSection::PullRequest, pull_request_url);
         local_commit.message_changed = true;
 
+        // Anchor the PR association to this commit's change-id (not its
+        // OID), so it survives `jj rebase`/`jj squash` and description
+        // edits that rewrite the OID but keep the change-id stable. Also
+        // record the content hash as a fallback, for the rarer case where
+        // even the change-id is replaced (e.g. `jj duplicate`).
+        let change_id = jj.get_change_id_for_commit(local_commit.oid)?;
+        jj.record_pull_request_for_change_id(&change_id, pull_request_number)?;
+        if let Ok(content_hash) = jj.get_content_hash_for_commit(local_commit.oid) {
+            jj.record_pull_request_for_content_hash(content_hash, pull_request_number)?;
+        }
+
         let result = gh
             .request_reviewers(pull_request_number, requested_reviewers)
             .await;
@@ -654,13 +952,287 @@ async fn diff_impl(
         }
     }
 
+    Ok(pull_request_branch)
+}
+
+/// Reviewers/teams owning `changed_paths` according to the repo's
+/// CODEOWNERS file (checked at the locations GitHub itself recognises, in
+/// the same order), using the usual "last matching pattern wins" rule,
+/// applied independently per path. Entries are returned verbatim from the
+/// file (e.g. `@octocat`, `@my-org/my-team`).
+fn codeowners_for_paths(repo_workdir: &std::path::Path, changed_paths: &[String]) -> Vec<String> {
+    const CANDIDATE_PATHS: &[&str] = &[".github/CODEOWNERS", "CODEOWNERS", "docs/CODEOWNERS"];
+
+    let Some(contents) = CANDIDATE_PATHS
+        .iter()
+        .find_map(|candidate| std::fs::read_to_string(repo_workdir.join(candidate)).ok())
+    else {
+        return Vec::new();
+    };
+
+    let rules: Vec<(&str, Vec<&str>)> = contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let mut parts = line.split_whitespace();
+            let pattern = parts.next()?;
+            Some((pattern, parts.collect()))
+        })
+        .collect();
+
+    let mut owners = Vec::new();
+    for path in changed_paths {
+        if let Some((_, path_owners)) = rules
+            .iter()
+            .rev()
+            .find(|(pattern, _)| codeowners_pattern_matches(pattern, path))
+        {
+            for owner in path_owners {
+                if !owners.iter().any(|o: &String| o == owner) {
+                    owners.push(owner.to_string());
+                }
+            }
+        }
+    }
+    owners
+}
+
+/// A deliberately small subset of CODEOWNERS' gitignore-style glob syntax: a
+/// `*` wildcard within a path segment (e.g. `*.rs`), a `/`-anchored path
+/// (matched segment by segment, so `/docs/` also covers everything beneath
+/// `docs/`), or a bare name that matches at any directory depth. Good
+/// enough for the common case; `**` isn't supported.
+fn codeowners_pattern_matches(pattern: &str, path: &str) -> bool {
+    let pattern = pattern.trim_end_matches('/');
+    let anchored = pattern.starts_with('/') || pattern.contains('/');
+    let pattern = pattern.trim_start_matches('/');
+
+    if anchored {
+        let pattern_segments: Vec<&str> = pattern.split('/').collect();
+        let path_segments: Vec<&str> = path.split('/').collect();
+        path_segments.len() >= pattern_segments.len()
+            && pattern_segments
+                .iter()
+                .zip(path_segments.iter())
+                .all(|(p, s)| glob_segment_matches(p, s))
+    } else {
+        path.split('/').any(|segment| glob_segment_matches(pattern, segment))
+    }
+}
+
+/// Matches a single path segment (no `/`) against a pattern that may
+/// contain `*` wildcards.
+fn glob_segment_matches(pattern: &str, segment: &str) -> bool {
+    let parts: Vec<&str> = pattern.split('*').collect();
+    if parts.len() == 1 {
+        return pattern == segment;
+    }
+
+    let mut pos = 0;
+    for (i, part) in parts.iter().enumerate() {
+        if part.is_empty() {
+            continue;
+        }
+        if i == 0 {
+            if !segment[pos..].starts_with(part) {
+                return false;
+            }
+            pos += part.len();
+        } else if i == parts.len() - 1 {
+            return segment.len() >= pos + part.len() && segment[pos..].ends_with(part);
+        } else if let Some(found) = segment[pos..].find(part) {
+            pos += found + part.len();
+        } else {
+            return false;
+        }
+    }
+    true
+}
+
+/// Warns (and, under `config.require_trusted_base_branch`, errors out)
+/// if `base_branch_name` isn't one of `config.trusted_base_branches` - a
+/// list of glob patterns (e.g. `main`, `release-*`) of integration targets
+/// the project actually wants PRs based on. Without this, a stack
+/// accidentally rebased onto (or a `--base` typo pointing at) some other
+/// branch silently submits, and the resulting PR shows every commit since
+/// that branch diverged from master instead of just the intended change.
+fn check_trusted_base_branch(
+    config: &crate::config::Config,
+    base_branch_name: &str,
+) -> Result<()> {
+    if config.trusted_base_branches.is_empty()
+        || config
+            .trusted_base_branches
+            .iter()
+            .any(|pattern| glob_segment_matches(pattern, base_branch_name))
+    {
+        return Ok(());
+    }
+
+    output(
+        "⚠️",
+        &formatdoc!(
+            "This stack is based on '{found}', which is not one of this \
+             project's trusted base branches ({expected}).
+             If that's intentional, pass the right target explicitly, e.g. \
+             `spr diff --base <branch>`. If it's not, you may have an old \
+             or wrongly-named local branch checked out - rebase onto the \
+             branch you meant to target before submitting.",
+            found = base_branch_name,
+            expected = config.trusted_base_branches.join(", "),
+        ),
+    )?;
+
+    if config.require_trusted_base_branch {
+        return Err(Error::new(format!(
+            "Refusing to push: '{}' is not a trusted base branch.",
+            base_branch_name
+        )));
+    }
+
+    Ok(())
+}
+
+/// Before `diff_impl` moves `branch_name` (or confirms an unchanged base
+/// branch hasn't moved), compare the remote's current value against what we
+/// last pushed and what we're about to push now - a three-way merge in the
+/// same spirit as the one jj itself does when importing git refs that moved
+/// underneath it. A clean fast-forward, or a remote that already has our
+/// new value, needs no input. A real divergence - the remote moved to
+/// something that's neither our recorded base nor our new value - is
+/// surfaced to the user instead of being silently overwritten.
+async fn resolve_remote_divergence(
+    jj: &crate::jj::Jujutsu,
+    remote_name: &str,
+    branch_name: &str,
+    fallback_base_oid: Oid,
+    new_oid: Oid,
+    force: bool,
+) -> Result<()> {
+    if force {
+        return Ok(());
+    }
+
+    match jj.check_remote_branch_status(remote_name, branch_name, fallback_base_oid, new_oid)? {
+        crate::jj::RemoteBranchStatus::UpToDate | crate::jj::RemoteBranchStatus::AlreadyPushed => {
+            Ok(())
+        }
+        crate::jj::RemoteBranchStatus::Diverged { base, remote } => {
+            output(
+                "⚠️",
+                &format!(
+                    "{} has diverged: we expected it at {}, but the remote is now at {} \
+                     (we want to push {})",
+                    branch_name, base, remote, new_oid
+                ),
+            )?;
+
+            let prompt_branch_name = branch_name.to_string();
+            let choice = tokio::task::spawn_blocking(move || {
+                dialoguer::Select::new()
+                    .with_prompt(format!(
+                        "How do you want to resolve the divergence on {}?",
+                        prompt_branch_name
+                    ))
+                    .items(&[
+                        "Abort - leave the remote branch as is so I can look at it",
+                        "Overwrite - push our version anyway (same as --force)",
+                    ])
+                    .default(0)
+                    .interact()
+            })
+            .await??;
+
+            if choice == 1 {
+                return Ok(());
+            }
+
+            Err(Error::new(format!(
+                "Aborted: {} has diverged from what spr expected (expected {}, found {}). \
+                 Fetch and inspect the remote commit to decide what to do with it, or pass \
+                 --force to overwrite it.",
+                branch_name, base, remote
+            )))
+        }
+    }
+}
+
+/// Everything `print_dry_run_plan` needs to describe what `--dry-run` would
+/// have pushed for one commit, gathered at the point where `diff_impl` has
+/// finished all local tree/commit construction but hasn't touched GitHub or
+/// the remote yet.
+struct DryRunPlan<'a> {
+    short_id: &'a str,
+    is_new_pull_request: bool,
+    pull_request_branch: &'a crate::github::GitHubBranch,
+    pr_head_oid: Oid,
+    new_pr_head_oid: Oid,
+    base_branch_name: &'a str,
+    base_branch_parents: &'a [Oid],
+    needs_merging_master: bool,
+}
+
+/// Prints what `diff_impl` would have pushed for one commit under
+/// `--dry-run`, modeled as a small replay plan: which ref would move to
+/// which new commit, what it would be based on, and whether that base
+/// itself needs a master merge. Nothing here touches the remote or GitHub.
+fn print_dry_run_plan(plan: DryRunPlan) -> Result<()> {
+    output(
+        "📝",
+        &format!(
+            "[dry-run] {} Pull Request for commit {}",
+            if plan.is_new_pull_request {
+                "Would create"
+            } else {
+                "Would update"
+            },
+            plan.short_id,
+        ),
+    )?;
+
+    output(
+        "  ",
+        &format!(
+            "refs/heads/{} ⇐ {} (was {})",
+            plan.pull_request_branch.branch_name(),
+            plan.new_pr_head_oid,
+            plan.pr_head_oid,
+        ),
+    )?;
+
+    if plan.base_branch_parents.is_empty() {
+        output("  ", &format!("base: {}", plan.base_branch_name))?;
+    } else {
+        output(
+            "  ",
+            &format!(
+                "base: {} ⇐ new commit over parent(s) {}",
+                plan.base_branch_name,
+                plan.base_branch_parents
+                    .iter()
+                    .map(|oid| oid.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            ),
+        )?;
+    }
+
+    output(
+        "  ",
+        &format!(
+            "needs master merge: {}",
+            if plan.needs_merging_master { "yes" } else { "no" },
+        ),
+    )?;
+
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::fs;
+    use std::{collections::HashMap, fs};
     use tempfile::TempDir;
 
     fn create_test_config() -> crate::config::Config {
@@ -749,8 +1321,14 @@ mod tests {
             draft: false,
             message: None,
             cherry_pick: false,
+            allow_conflicts: false,
             base: None,
             revision: None,
+            revset: None,
+            stack: false,
+            dry_run: false,
+            force: false,
+            owners_reviewers: false,
         };
 
         assert!(!opts.all);
@@ -769,8 +1347,14 @@ mod tests {
             draft: false,
             message: None,
             cherry_pick: false,
+            allow_conflicts: false,
             base: Some("main".to_string()),
             revision: None,
+            revset: None,
+            stack: false,
+            dry_run: false,
+            force: false,
+            owners_reviewers: false,
         };
 
         assert_eq!(opts.base, Some("main".to_string()));
@@ -794,8 +1378,14 @@ mod tests {
             draft: false,
             message: None,
             cherry_pick: false,
+            allow_conflicts: false,
             base: Some("main".to_string()),
             revision: None,
+            revset: None,
+            stack: false,
+            dry_run: false,
+            force: false,
+            owners_reviewers: false,
         };
 
         assert_eq!(opts_with_base.base.as_deref(), Some("main"));
@@ -807,8 +1397,14 @@ mod tests {
             draft: false,
             message: None,
             cherry_pick: false,
+            allow_conflicts: false,
             base: Some("trunk()".to_string()),
             revision: None,
+            revset: None,
+            stack: false,
+            dry_run: false,
+            force: false,
+            owners_reviewers: false,
         };
 
         assert_eq!(opts_with_trunk.base.as_deref(), Some("trunk()"));
@@ -822,8 +1418,14 @@ mod tests {
             draft: false,
             message: None,
             cherry_pick: false,
+            allow_conflicts: false,
             base: Some("trunk()".to_string()),
             revision: None,
+            revset: None,
+            stack: false,
+            dry_run: false,
+            force: false,
+            owners_reviewers: false,
         };
 
         // When --all is specified, it should work with base revisions
@@ -840,8 +1442,14 @@ mod tests {
             draft: true,
             message: Some("Update message".to_string()),
             cherry_pick: false,
+            allow_conflicts: false,
             base: Some("trunk()".to_string()),
             revision: None,
+            revset: None,
+            stack: false,
+            dry_run: false,
+            force: false,
+            owners_reviewers: false,
         };
 
         assert!(opts.all);
@@ -852,14 +1460,295 @@ mod tests {
         assert_eq!(opts.base.as_deref(), Some("trunk()"));
     }
 
-    // Integration tests would require more complex setup with actual Git repositories
-    // and proper mocking of GitHub API calls. The tests above focus on:
-    // 1. Option parsing and validation
-    // 2. Data structure correctness
-    // 3. Basic logic flow verification
-    //
-    // For full integration testing, consider:
-    // - Mocking GitHub API responses
-    // - Creating test repositories with specific commit structures
-    // - Testing the interaction between revision specification and commit preparation
+    #[test]
+    fn test_codeowners_pattern_matches() {
+        assert!(codeowners_pattern_matches("*", "anything/at/all.rs"));
+        assert!(codeowners_pattern_matches("/docs/", "docs/guide.md"));
+        assert!(!codeowners_pattern_matches("/docs/", "src/docs/guide.md"));
+        assert!(codeowners_pattern_matches("*.rs", "src/lib.rs"));
+        assert!(codeowners_pattern_matches("Cargo.toml", "Cargo.toml"));
+        assert!(codeowners_pattern_matches("src", "src/lib.rs"));
+        assert!(!codeowners_pattern_matches("src", "other/src_thing.rs"));
+    }
+
+    #[test]
+    fn test_codeowners_for_paths_last_match_wins() {
+        let temp_dir = TempDir::new().expect("Failed to create temp directory");
+        fs::write(
+            temp_dir.path().join("CODEOWNERS"),
+            "* @default-owner\n/src/ @src-team\n/src/special.rs @special-owner\n",
+        )
+        .expect("Failed to write CODEOWNERS");
+
+        let owners = codeowners_for_paths(
+            temp_dir.path(),
+            &[
+                "README.md".to_string(),
+                "src/lib.rs".to_string(),
+                "src/special.rs".to_string(),
+            ],
+        );
+
+        assert!(owners.contains(&"@default-owner".to_string()));
+        assert!(owners.contains(&"@src-team".to_string()));
+        assert!(owners.contains(&"@special-owner".to_string()));
+    }
+
+    #[test]
+    fn test_codeowners_for_paths_missing_file() {
+        let temp_dir = TempDir::new().expect("Failed to create temp directory");
+        let owners = codeowners_for_paths(temp_dir.path(), &["README.md".to_string()]);
+        assert!(owners.is_empty());
+    }
+
+    fn create_test_config_with_trusted_bases(
+        trusted_base_branches: Vec<String>,
+        require_trusted_base_branch: bool,
+    ) -> crate::config::Config {
+        let mut config = create_test_config();
+        config.trusted_base_branches = trusted_base_branches;
+        config.require_trusted_base_branch = require_trusted_base_branch;
+        config
+    }
+
+    #[test]
+    fn test_check_trusted_base_branch_no_restriction_configured() {
+        let config = create_test_config_with_trusted_bases(vec![], false);
+        assert!(check_trusted_base_branch(&config, "some-random-branch").is_ok());
+    }
+
+    #[test]
+    fn test_check_trusted_base_branch_matches_glob() {
+        let config = create_test_config_with_trusted_bases(
+            vec!["main".to_string(), "release-*".to_string()],
+            false,
+        );
+        assert!(check_trusted_base_branch(&config, "main").is_ok());
+        assert!(check_trusted_base_branch(&config, "release-1.0").is_ok());
+    }
+
+    #[test]
+    fn test_check_trusted_base_branch_warns_but_does_not_abort() {
+        let config = create_test_config_with_trusted_bases(vec!["main".to_string()], false);
+        // Not in the trusted list, but not strict either - just a warning.
+        assert!(check_trusted_base_branch(&config, "some-feature-branch").is_ok());
+    }
+
+    #[test]
+    fn test_check_trusted_base_branch_aborts_when_strict() {
+        let config = create_test_config_with_trusted_bases(vec!["main".to_string()], true);
+        assert!(check_trusted_base_branch(&config, "some-feature-branch").is_err());
+    }
+
+    /// In-memory fake of `crate::github::GitHubApi`, so the `diff` flow can
+    /// be exercised end to end without any network access. Records every
+    /// Pull Request created, the message sections and branches it was
+    /// created with, and any reviewers requested on it, and hands out
+    /// incrementing PR numbers the way the real API would.
+    #[derive(Clone, Default)]
+    struct FakeGitHub {
+        state: std::sync::Arc<std::sync::Mutex<FakeGitHubState>>,
+    }
+
+    #[derive(Default)]
+    struct FakeGitHubState {
+        next_number: u64,
+        pull_requests: HashMap<u64, FakePullRequest>,
+    }
+
+    #[derive(Debug, Clone)]
+    struct FakePullRequest {
+        base: String,
+        head: String,
+        draft: bool,
+        sections: MessageSectionsMap,
+        requested_reviewers: Option<PullRequestRequestReviewers>,
+    }
+
+    impl crate::github::GitHubApi for FakeGitHub {
+        async fn get_pull_request(self, number: u64) -> Result<PullRequest> {
+            let state = self.state.lock().expect("FakeGitHub lock poisoned");
+            if !state.pull_requests.contains_key(&number) {
+                return Err(Error::new(format!(
+                    "FakeGitHub has no Pull Request #{}",
+                    number
+                )));
+            }
+
+            Ok(PullRequest {
+                number,
+                state: PullRequestState::Open,
+                ..Default::default()
+            })
+        }
+
+        async fn create_pull_request(
+            &mut self,
+            message: &MessageSectionsMap,
+            base: String,
+            head: String,
+            draft: bool,
+        ) -> Result<u64> {
+            let mut state = self.state.lock().expect("FakeGitHub lock poisoned");
+            state.next_number += 1;
+            let number = state.next_number;
+            state.pull_requests.insert(
+                number,
+                FakePullRequest {
+                    base,
+                    head,
+                    draft,
+                    sections: message.clone(),
+                    requested_reviewers: None,
+                },
+            );
+            Ok(number)
+        }
+
+        async fn update_pull_request(
+            &mut self,
+            number: u64,
+            update: PullRequestUpdate,
+        ) -> Result<()> {
+            let mut state = self.state.lock().expect("FakeGitHub lock poisoned");
+            if let Some(pr) = state.pull_requests.get_mut(&number) {
+                if let Some(base) = update.base {
+                    pr.base = base;
+                }
+            }
+            Ok(())
+        }
+
+        async fn request_reviewers(
+            &mut self,
+            number: u64,
+            reviewers: PullRequestRequestReviewers,
+        ) -> Result<()> {
+            let mut state = self.state.lock().expect("FakeGitHub lock poisoned");
+            if let Some(pr) = state.pull_requests.get_mut(&number) {
+                pr.requested_reviewers = Some(reviewers);
+            }
+            Ok(())
+        }
+    }
+
+    /// Builds a linear stack of commits in a temp, colocated jj/git repo for
+    /// integration tests - the `diff` command's equivalent of the
+    /// `CommitGraphBuilder` helper jj's own test suite uses to construct
+    /// multi-commit graphs without hand-rolling `jj` CLI calls in every
+    /// test.
+    struct CommitGraphBuilder {
+        repo_path: std::path::PathBuf,
+    }
+
+    impl CommitGraphBuilder {
+        fn init() -> (TempDir, Self) {
+            let temp_dir = TempDir::new().expect("Failed to create temp directory");
+            let repo_path = temp_dir.path().to_path_buf();
+
+            let output = std::process::Command::new("jj")
+                .args(["git", "init", "--colocate"])
+                .current_dir(&repo_path)
+                .output()
+                .expect("Failed to run jj git init");
+            assert!(
+                output.status.success(),
+                "jj git init failed: {}",
+                String::from_utf8_lossy(&output.stderr)
+            );
+
+            for (key, value) in [("user.name", "Test User"), ("user.email", "test@example.com")] {
+                let _ = std::process::Command::new("jj")
+                    .args(["config", "set", "--repo", key, value])
+                    .current_dir(&repo_path)
+                    .output();
+            }
+
+            (temp_dir, CommitGraphBuilder { repo_path })
+        }
+
+        /// Create one commit with the given message and file content on top
+        /// of whatever is currently checked out.
+        fn commit(&self, message: &str, content: &str) -> Oid {
+            fs::write(self.repo_path.join("test.txt"), content).expect("Failed to write test file");
+
+            let output = std::process::Command::new("jj")
+                .args(["commit", "-m", message])
+                .current_dir(&self.repo_path)
+                .output()
+                .expect("Failed to run jj commit");
+            assert!(
+                output.status.success(),
+                "jj commit failed: {}",
+                String::from_utf8_lossy(&output.stderr)
+            );
+
+            let output = std::process::Command::new("jj")
+                .args(["log", "--no-graph", "-r", "@-", "--template", "commit_id"])
+                .current_dir(&self.repo_path)
+                .output()
+                .expect("Failed to get commit id");
+
+            Oid::from_str(String::from_utf8_lossy(&output.stdout).trim())
+                .expect("Invalid commit id from jj")
+        }
+
+        /// Create a whole stack of commits at once, one per message, each on
+        /// top of the last, and return their OIDs bottom to top.
+        fn build_stack(&self, messages: &[&str]) -> Vec<Oid> {
+            messages
+                .iter()
+                .enumerate()
+                .map(|(i, message)| self.commit(message, &format!("content {}", i)))
+                .collect()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_diff_creates_pull_request_end_to_end() {
+        let (_temp_dir, builder) = CommitGraphBuilder::init();
+        builder.build_stack(&["Base commit"]);
+        builder.commit("My new feature", "feature content");
+
+        let git_repo =
+            git2::Repository::open(&builder.repo_path).expect("Failed to open git repository");
+        let jj = crate::jj::Jujutsu::new(git_repo).expect("Failed to create Jujutsu instance");
+        let config = create_test_config();
+        let mut gh = FakeGitHub::default();
+
+        let opts = DiffOptions {
+            all: false,
+            update_message: false,
+            draft: false,
+            message: None,
+            cherry_pick: false,
+            allow_conflicts: false,
+            base: None,
+            revision: None,
+            revset: None,
+            stack: false,
+            dry_run: false,
+            force: false,
+            owners_reviewers: false,
+        };
+
+        diff(opts, &jj, &mut gh, &config)
+            .await
+            .expect("diff should succeed");
+
+        let state = gh.state.lock().expect("FakeGitHub lock poisoned");
+        assert_eq!(
+            state.pull_requests.len(),
+            1,
+            "expected exactly one Pull Request to be created"
+        );
+        let pull_request = state.pull_requests.values().next().unwrap();
+        assert_eq!(pull_request.base, config.master_ref.branch_name());
+        assert!(pull_request.head.starts_with("spr/test/"));
+        assert!(!pull_request.draft);
+        assert!(pull_request
+            .sections
+            .get(&MessageSection::Title)
+            .is_some_and(|title| title.contains("My new feature")));
+    }
 }
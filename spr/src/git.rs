@@ -17,11 +17,9 @@ use crate::{
     error::{Error, Result, ResultExt},
     github::GitHubBranch,
     message::{build_commit_message, parse_message, MessageSection, MessageSectionsMap},
-    utils::run_command,
 };
 use debug_ignore::DebugIgnore;
 use git2::Oid;
-use git2_ext::ops::UserSign;
 
 #[derive(Debug)]
 pub struct PreparedCommit {
@@ -95,11 +93,16 @@ impl Git {
         revision: Option<&str>,
     ) -> Result<Vec<PreparedCommit>> {
         if let Some(rev) = revision {
-            // Use Jujutsu revision if specified
+            // Use Jujutsu revision if specified. The revset can resolve to
+            // more than one commit (e.g. `mine() & ~::main`), so prepare all
+            // of them rather than assuming a single match, in topological
+            // order.
             if let Some(jj) = &self.jj {
-                let commit_oid = jj.cli.resolve_revision_to_commit_id(rev)?;
-                let prepared_commit = self.lock_and_prepare_commit(config, commit_oid)?;
-                return Ok(vec![prepared_commit]);
+                let commit_oids = jj.cli.resolve_revision_to_commit_ids(rev)?;
+                return commit_oids
+                    .into_iter()
+                    .map(|oid| self.lock_and_prepare_commit(config, oid))
+                    .collect();
             } else {
                 return Err(Error::new(
                     "--revision option is only supported in Jujutsu repositories".to_string(),
@@ -190,9 +193,20 @@ impl Git {
             let new_parent_commit = repo.find_commit(new_parent_oid)?;
             let commit = repo.find_commit(prepared_commit.oid)?;
 
-            let index = repo.cherrypick_commit(&commit, &new_parent_commit)?;
+            let mut index = repo.cherrypick_commit(&commit, &new_parent_commit)?;
             if index.has_conflicts() {
-                return Err(Error::new("Rebase failed due to merge conflicts"));
+                // Rather than aborting the whole rebase, materialize the
+                // conflicting hunks as conflict markers in the tree, the way
+                // jj keeps conflicted commits as first-class members of the
+                // commit graph instead of stopping to resolve them. The
+                // rebase continues onto the remaining commits, and the user
+                // resolves the markers afterwards like any other merge
+                // conflict.
+                eprintln!(
+                    "[spr] commit {} has conflicts - leaving conflict markers in the tree",
+                    prepared_commit.short_id
+                );
+                index = repo.resolve_conflicts_with_markers(index)?;
             }
 
             let tree_oid = repo.write_index(index)?;
@@ -285,20 +299,18 @@ impl Git {
         };
 
         if !missing_commit_oids.is_empty() {
-            let mut command = tokio::process::Command::new("git");
-            command
-                .arg("fetch")
-                .arg("--no-write-fetch-head")
-                .arg("--")
-                .arg(remote);
-
-            for oid in missing_commit_oids {
-                command.arg(format!("{}", oid));
-            }
+            let refspecs: Vec<String> = missing_commit_oids
+                .into_iter()
+                .map(|oid| oid.to_string())
+                .collect();
+
+            self.lock_repo().fetch(remote, &refspecs)?;
 
-            run_command(&mut command)
-                .await
-                .reword("git fetch failed".to_string())?;
+            // In a non-colocated repo, the fetch above landed new refs in
+            // jj's private backing store, invisible to jj until imported.
+            if let Some(jj) = &self.jj {
+                jj.sync_from_git()?;
+            }
         }
 
         Ok(())
@@ -306,20 +318,17 @@ impl Git {
 
     pub async fn fetch_from_remote(refs: &[&GitHubBranch], remote: &str) -> Result<()> {
         if !refs.is_empty() {
-            let mut command = tokio::process::Command::new("git");
-            command
-                .arg("fetch")
-                .arg("--no-write-fetch-head")
-                .arg("--")
-                .arg(remote);
-
-            for ghref in refs {
-                command.arg(ghref.on_github());
-            }
+            let refspecs: Vec<String> = refs.iter().map(|ghref| ghref.on_github()).collect();
+
+            // There's no `Git` instance handy here, so discover the repo the
+            // same way `git` itself would: from the current directory.
+            let repo = git2::Repository::discover(".").context("failed to find git repository")?;
+            let dot_git_path = repo.path().to_owned();
+            GitRepo::new(repo)?.fetch(remote, &refspecs)?;
 
-            run_command(&mut command)
-                .await
-                .reword("git fetch failed".to_string())?;
+            if let Ok(jj) = JujutsuRepo::from_git_path(&dot_git_path) {
+                jj.sync_from_git()?;
+            }
         }
 
         Ok(())
@@ -528,7 +537,7 @@ impl GitRepo {
             .config()
             .context("failed to read repo config".to_owned())?;
         // If commit.gpgsign is set, then attempt to obtain the signing info.
-        let sign = CommitSign::new(&repo, &config);
+        let sign = CommitSign::new(&config);
 
         Ok(Self {
             repo: DebugIgnore(repo),
@@ -588,9 +597,21 @@ impl GitRepo {
         parents: &[&git2::Commit<'_>],
         run_post_rewrite_hooks: RunPostRewriteRebaseHooks,
     ) -> Result<Oid> {
-        let sign = self.sign.as_dyn_sign();
-        let new_oid =
-            git2_ext::ops::commit(&self.repo, author, committer, message, tree, parents, sign)?;
+        let new_oid = match self.sign.signer() {
+            Some(signer) => {
+                let commit_buf = self
+                    .repo
+                    .commit_create_buffer(author, committer, message, tree, parents)?;
+                let commit_content = std::str::from_utf8(&commit_buf)
+                    .context("commit object was not valid UTF-8".to_string())?;
+                let signature = signer.sign(&commit_buf)?;
+                self.repo
+                    .commit_signed(commit_content, &signature, Some("gpgsig"))?
+            }
+            None => self
+                .repo
+                .commit(None, author, committer, message, tree, parents)?,
+        };
 
         match run_post_rewrite_hooks {
             RunPostRewriteRebaseHooks::Yes { prepared_commit } => {
@@ -640,25 +661,140 @@ impl GitRepo {
     fn write_index(&self, mut index: git2::Index) -> Result<Oid> {
         Ok(index.write_tree_to(&self.repo)?)
     }
+
+    /// Replace every conflicting entry in `index` with a single staged blob
+    /// containing standard `<<<<<<<`/`=======`/`>>>>>>>` conflict markers,
+    /// so the index (and the tree built from it) no longer has conflicts and
+    /// can be committed, with the markers left for the user to resolve.
+    fn resolve_conflicts_with_markers(&self, mut index: git2::Index) -> Result<git2::Index> {
+        let conflicts: Vec<_> = index
+            .conflicts()?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+
+        for conflict in conflicts {
+            let ancestor = conflict.ancestor.as_ref();
+            let our = conflict.our.as_ref();
+            let their = conflict.their.as_ref();
+
+            let path = our
+                .or(their)
+                .or(ancestor)
+                .map(|e| e.path.clone())
+                .ok_or_else(|| Error::new("Conflict entry had no path".to_string()))?;
+            let mode = our.or(their).or(ancestor).map(|e| e.mode).unwrap_or(0o100644);
+
+            let mut merge_opts = git2::MergeFileOptions::new();
+            merge_opts.style_merge(true);
+            let merge_result =
+                self.repo
+                    .merge_file_from_index(ancestor, our, their, Some(&merge_opts))?;
+
+            let blob_oid = self.repo.blob(merge_result.content())?;
+
+            index.remove_path(Path::new(&String::from_utf8_lossy(&path).into_owned()))?;
+            index.add(&git2::IndexEntry {
+                ctime: git2::IndexTime::new(0, 0),
+                mtime: git2::IndexTime::new(0, 0),
+                dev: 0,
+                ino: 0,
+                mode,
+                uid: 0,
+                gid: 0,
+                file_size: merge_result.content().len() as u32,
+                id: blob_oid,
+                flags: 0,
+                flags_extended: 0,
+                path,
+            })?;
+        }
+
+        Ok(index)
+    }
+
+    /// Fetch `refspecs` (commit ids or ref names) from `remote_name` using
+    /// libgit2 directly, rather than shelling out to the `git` binary. This
+    /// gives us proper credential negotiation (ssh-agent, credential
+    /// helpers) via callbacks, and transfer progress on stderr, instead of
+    /// relying on whatever terminal handling the `git` subprocess happens to
+    /// do.
+    fn fetch(&self, remote_name: &str, refspecs: &[String]) -> Result<()> {
+        let mut remote = self.repo.find_remote(remote_name)?;
+
+        let mut callbacks = git2::RemoteCallbacks::new();
+        callbacks.credentials(|url, username_from_url, allowed_types| {
+            if allowed_types.contains(git2::CredentialType::SSH_KEY) {
+                if let Some(username) = username_from_url {
+                    if let Ok(cred) = git2::Cred::ssh_key_from_agent(username) {
+                        return Ok(cred);
+                    }
+                }
+            }
+            if allowed_types.contains(git2::CredentialType::USER_PASS_PLAINTEXT) {
+                if let Ok(config) = git2::Config::open_default() {
+                    if let Ok(cred) = git2::Cred::credential_helper(&config, url, username_from_url)
+                    {
+                        return Ok(cred);
+                    }
+                }
+            }
+            git2::Cred::default()
+        });
+        callbacks.transfer_progress(|stats| {
+            if stats.received_objects() == stats.total_objects() {
+                eprint!(
+                    "\rResolving deltas {}/{}",
+                    stats.indexed_deltas(),
+                    stats.total_deltas()
+                );
+            } else if stats.total_objects() > 0 {
+                eprint!(
+                    "\rReceiving objects: {}/{} ({} bytes)",
+                    stats.received_objects(),
+                    stats.total_objects(),
+                    stats.received_bytes()
+                );
+            }
+            true
+        });
+
+        let mut fetch_options = git2::FetchOptions::new();
+        fetch_options.remote_callbacks(callbacks);
+
+        remote.fetch(refspecs, Some(&mut fetch_options), None)?;
+        eprintln!();
+
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
 enum CommitSign {
-    Enabled(DebugIgnore<UserSign>),
+    Enabled(Box<dyn Signer>),
     EnabledButError,
     Disabled,
 }
 
 impl CommitSign {
-    fn new(repo: &git2::Repository, config: &git2::Config) -> Self {
+    fn new(config: &git2::Config) -> Self {
         match config.get_bool("commit.gpgsign") {
-            Ok(true) => match UserSign::from_config(repo, config) {
-                Ok(sign) => Self::Enabled(DebugIgnore(sign)),
-                Err(err) => {
-                    eprintln!("[spr] unable to obtain signing info: {}", err);
-                    Self::EnabledButError
+            // `commit.gpgsign` is just what git calls the knob that turns
+            // signing on at all - which backend that signing actually uses
+            // is a separate question, answered by `gpg.format`.
+            Ok(true) => {
+                let format = config
+                    .get_string("gpg.format")
+                    .unwrap_or_else(|_| "openpgp".to_string());
+                match build_signer(config, &format) {
+                    Ok(signer) => Self::Enabled(signer),
+                    Err(err) => {
+                        eprintln!(
+                            "[spr] unable to obtain signing info (gpg.format = {}): {}",
+                            format, err
+                        );
+                        Self::EnabledButError
+                    }
                 }
-            },
+            }
             Ok(false) => Self::Disabled,
             Err(err) => {
                 if err.code() == git2::ErrorCode::NotFound {
@@ -671,14 +807,173 @@ impl CommitSign {
         }
     }
 
-    fn as_dyn_sign(&self) -> Option<&dyn git2_ext::ops::Sign> {
+    fn signer(&self) -> Option<&dyn Signer> {
         match self {
-            Self::Enabled(sign) => Some(&**sign),
+            Self::Enabled(signer) => Some(signer.as_ref()),
             _ => None,
         }
     }
 }
 
+/// Produces an armored detached signature for the raw bytes of a
+/// not-yet-created commit object, mirroring the `Signer` trait radicle uses
+/// for the same purpose. `CommitSign` picks one of these based on
+/// `gpg.format`, and `GitRepo::commit` routes every derived, rewritten, or
+/// rebased commit jj-spr creates through whichever one is active, so it
+/// carries the same signature type the user's own `jj`/`git` commits would.
+trait Signer: std::fmt::Debug {
+    fn sign(&self, buffer: &[u8]) -> Result<String>;
+}
+
+fn build_signer(config: &git2::Config, format: &str) -> Result<Box<dyn Signer>> {
+    match format {
+        "ssh" => Ok(Box::new(SshSigner::from_config(config)?)),
+        "x509" => Ok(Box::new(GpgSigner::from_config(config, "gpgsm")?)),
+        "openpgp" => Ok(Box::new(GpgSigner::from_config(config, "gpg")?)),
+        other => Err(Error::new(format!("unsupported gpg.format '{}'", other))),
+    }
+}
+
+/// Signs via `gpg.program` (OpenPGP) or, when `gpg.format = x509`,
+/// `gpg.program` defaulting to `gpgsm`. Because git resolves the signing
+/// program this way rather than hardcoding `gpg`, this is also how
+/// sigstore-style keyless signing works: pointing `gpg.program` at a tool
+/// like `gitsign` gets the same treatment as real GPG, with no separate
+/// backend needed.
+#[derive(Debug)]
+struct GpgSigner {
+    program: String,
+    signing_key: Option<String>,
+}
+
+impl GpgSigner {
+    fn from_config(config: &git2::Config, default_program: &str) -> Result<Self> {
+        let program = config
+            .get_string("gpg.program")
+            .unwrap_or_else(|_| default_program.to_string());
+        let signing_key = config.get_string("user.signingkey").ok();
+        Ok(Self {
+            program,
+            signing_key,
+        })
+    }
+}
+
+impl Signer for GpgSigner {
+    fn sign(&self, buffer: &[u8]) -> Result<String> {
+        use std::io::Write;
+
+        let mut command = Command::new(&self.program);
+        command.args(["--status-fd=2", "--detach-sign", "--armor"]);
+        if let Some(key) = &self.signing_key {
+            command.arg("--local-user").arg(key);
+        }
+        command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+
+        let mut child = command
+            .spawn()
+            .context(format!("failed to spawn {}", self.program))?;
+        child
+            .stdin
+            .take()
+            .expect("stdin was piped")
+            .write_all(buffer)
+            .context(format!("failed to write commit data to {}", self.program))?;
+
+        let output = child
+            .wait_with_output()
+            .context(format!("failed to wait for {}", self.program))?;
+        if !output.status.success() {
+            return Err(Error::new(format!(
+                "{} failed: {}",
+                self.program,
+                String::from_utf8_lossy(&output.stderr)
+            )));
+        }
+
+        String::from_utf8(output.stdout)
+            .context(format!("{} produced a non-UTF-8 signature", self.program))
+    }
+}
+
+/// Signs via `ssh-keygen -Y sign`, the mechanism `gpg.format = ssh` uses.
+/// Unlike GPG, `ssh-keygen -Y sign` only signs files, not stdin, and writes
+/// the armored signature out to `<file>.sig` next to the input rather than
+/// to its own stdout - so, like `git` itself, we round-trip the commit
+/// buffer through a temporary file.
+#[derive(Debug)]
+struct SshSigner {
+    program: String,
+    signing_key: String,
+}
+
+impl SshSigner {
+    fn from_config(config: &git2::Config) -> Result<Self> {
+        let program = config
+            .get_string("gpg.ssh.program")
+            .unwrap_or_else(|_| "ssh-keygen".to_string());
+        let signing_key = config.get_string("user.signingkey").map_err(|_| {
+            Error::new("gpg.format = ssh requires user.signingkey to be set".to_string())
+        })?;
+        Ok(Self {
+            program,
+            signing_key,
+        })
+    }
+}
+
+impl Signer for SshSigner {
+    fn sign(&self, buffer: &[u8]) -> Result<String> {
+        let work_dir = std::env::temp_dir().join(format!("spr-ssh-sign-{}", std::process::id()));
+        std::fs::create_dir_all(&work_dir)
+            .context("failed to create temp dir for ssh signing".to_string())?;
+
+        let buffer_path = work_dir.join("commit");
+        std::fs::write(&buffer_path, buffer)
+            .context("failed to write commit buffer for ssh signing".to_string())?;
+
+        // `user.signingkey` can be a path to a key file, or (as `ssh` and
+        // `git` both accept) an inline public key - write the latter out so
+        // `-f` always has a file to read.
+        let key_path = if self.signing_key.contains(' ') {
+            let key_file = work_dir.join("key.pub");
+            std::fs::write(&key_file, &self.signing_key)
+                .context("failed to write inline signing key".to_string())?;
+            key_file
+        } else {
+            PathBuf::from(&self.signing_key)
+        };
+
+        let output = Command::new(&self.program)
+            .args(["-Y", "sign", "-n", "git", "-f"])
+            .arg(&key_path)
+            .arg(&buffer_path)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .output()
+            .context(format!("failed to spawn {}", self.program));
+
+        let signature = output.and_then(|output| {
+            if !output.status.success() {
+                return Err(Error::new(format!(
+                    "{} failed: {}",
+                    self.program,
+                    String::from_utf8_lossy(&output.stderr)
+                )));
+            }
+            std::fs::read_to_string(buffer_path.with_extension("sig"))
+                .context("failed to read ssh signature".to_string())
+        });
+
+        let _ = std::fs::remove_dir_all(&work_dir);
+
+        signature
+    }
+}
+
 #[derive(Clone, Copy, Debug)]
 enum RunPostRewriteRebaseHooks {
     Yes { prepared_commit: Oid },
@@ -688,30 +983,56 @@ enum RunPostRewriteRebaseHooks {
 #[derive(Clone, Debug)]
 struct JujutsuRepo {
     cli: JujutsuCli,
+    // Whether the `.git` directory we were constructed from sits directly in
+    // the jj workspace root (`jj git init --colocate`), as opposed to being
+    // jj's own private backing store (`jj git init`, no `--colocate`). A
+    // colocated repo's `.git` is kept in sync by jj on every `jj` invocation,
+    // so git2 calls against it always see up-to-date refs. A non-colocated
+    // repo's backing store is only ever touched by jj itself - anything we do
+    // to it with git2 (fetch, push) is invisible to jj until we explicitly
+    // `jj git import`/`jj git export`.
+    colocated: bool,
 }
 
 impl JujutsuRepo {
     fn from_git_path(dot_git_path: &Path) -> Result<Self> {
-        // This is a (colocated) jujutsu repo if:
-        // - git_path ends with .git
-        // - the path's parent is the same as what's returned by `jj root`
-
         let dot_git_path = dot_git_path.canonicalize()?;
-        if !dot_git_path.ends_with(".git") {
+
+        // This is a jujutsu repo if either:
+        // - (colocated) git_path ends with .git, and its parent is what `jj
+        //   root` returns for that directory, or
+        // - (non-colocated) git_path is jj's own backing store, tucked away
+        //   at <root>/.jj/repo/store/git, in which case the workspace root
+        //   is four directories up.
+        let (repo_path, colocated) = if dot_git_path.ends_with(".git") {
+            let repo_path = dot_git_path.parent().ok_or_else(|| {
+                Error::new(format!("git path {} has no parent", dot_git_path.display()))
+            })?;
+            (repo_path.to_owned(), true)
+        } else if dot_git_path.ends_with(Path::new(".jj/repo/store/git")) {
+            let repo_path = dot_git_path
+                .ancestors()
+                .nth(4)
+                .ok_or_else(|| {
+                    Error::new(format!(
+                        "git path {} is not nested inside a .jj directory",
+                        dot_git_path.display()
+                    ))
+                })?
+                .to_owned();
+            (repo_path, false)
+        } else {
             return Err(Error::new(format!(
-                "git path {} does not end with .git",
+                "git path {} is neither a colocated .git nor a jj-managed backing store",
                 dot_git_path.display()
             )));
-        }
-        let repo_path = dot_git_path.parent().ok_or_else(|| {
-            Error::new(format!("git path {} has no parent", dot_git_path.display()))
-        })?;
+        };
 
         // This is a _potential_ jj CLI -- we need to check if the actual root lines up.
         let jj_bin = get_jj_bin();
         let cli = JujutsuCli {
             jj_bin,
-            repo_path: repo_path.to_owned(),
+            repo_path: repo_path.clone(),
         };
 
         // Try fetching the root from the CLI.
@@ -721,13 +1042,37 @@ impl JujutsuRepo {
         // Ensure that the root is the same.
         if root != repo_path {
             return Err(Error::new(format!(
-                "git path {} is not colocated with jj root {}",
+                "git path {} does not belong to jj root {}",
                 dot_git_path.display(),
                 root.display()
             )));
         }
 
-        Ok(Self { cli })
+        Ok(Self { cli, colocated })
+    }
+
+    /// Make sure jj has noticed any refs that were written straight into the
+    /// backing git store by git2 (e.g. a fetch). A no-op for colocated
+    /// repos, which jj keeps in sync on its own.
+    fn sync_from_git(&self) -> Result<()> {
+        if !self.colocated {
+            self.cli
+                .run_captured_with_args(["git", "import"])
+                .reword("Failed to import git refs into jj".to_string())?;
+        }
+        Ok(())
+    }
+
+    /// Make sure jj's view of the repo has been written out to the backing
+    /// git store, so that a subsequent git2 call (e.g. a push) sees it. A
+    /// no-op for colocated repos, which jj keeps in sync on its own.
+    fn sync_to_git(&self) -> Result<()> {
+        if !self.colocated {
+            self.cli
+                .run_captured_with_args(["git", "export"])
+                .reword("Failed to export jj state to git".to_string())?;
+        }
+        Ok(())
     }
 
     fn rewrite_commit_messages(&self, commits: &[PreparedCommit]) -> Result<()> {
@@ -758,6 +1103,11 @@ impl JujutsuRepo {
             }
         }
 
+        // The `jj describe` calls above rewrote commits; push that out to
+        // the backing git store so a non-colocated repo's subsequent git2
+        // calls (e.g. the push in `diff_impl`) see the new commit IDs.
+        self.sync_to_git()?;
+
         Ok(())
     }
 }
@@ -874,6 +1224,37 @@ impl JujutsuCli {
         })
     }
 
+    /// Like `resolve_revision_to_commit_id`, but for revsets that may match
+    /// more than one commit (e.g. `--revision 'mine() & ~::main'`). Callers
+    /// rebuild the parent chain from `commits[0].parent_oid` forward, so
+    /// these need to come back oldest-first rather than `jj log`'s default
+    /// newest-first order.
+    fn resolve_revision_to_commit_ids(&self, revision: &str) -> Result<Vec<Oid>> {
+        let output = self.run_captured_with_args([
+            "log",
+            "--no-graph",
+            "--reversed",
+            "-r",
+            revision,
+            "--template",
+            "commit_id ++ \"\\n\"",
+        ])?;
+
+        output
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(|line| {
+                Oid::from_str(line).map_err(|e| {
+                    Error::new(format!(
+                        "Failed to parse commit ID '{}' from jj output: {}",
+                        line, e
+                    ))
+                })
+            })
+            .collect()
+    }
+
     fn run_captured_with_args<I, S>(&self, args: I) -> Result<String>
     where
         I: IntoIterator<Item = S>,
@@ -1174,6 +1555,33 @@ mod tests {
             assert!(git.jj.is_some());
         }
 
+        #[test]
+        fn test_jujutsu_non_colocated_repository_detection() {
+            let temp_dir = TempDir::new().expect("Failed to create temp directory");
+            let repo_path = temp_dir.path().to_path_buf();
+
+            // Initialize a non-colocated jj repository - no `.git` at the
+            // workspace root, just `.jj/repo/store/git`.
+            let output = Command::new("jj")
+                .args(["git", "init"])
+                .current_dir(&repo_path)
+                .output()
+                .expect("Failed to run jj git init");
+            if !output.status.success() {
+                panic!(
+                    "Failed to initialize jj repo: {}",
+                    String::from_utf8_lossy(&output.stderr)
+                );
+            }
+
+            assert!(!repo_path.join(".git").exists());
+            let dot_git_path = repo_path.join(".jj/repo/store/git");
+
+            let jj = JujutsuRepo::from_git_path(&dot_git_path)
+                .expect("Failed to detect non-colocated jj repository");
+            assert!(!jj.colocated);
+        }
+
         #[test]
         fn test_jujutsu_revision_resolution() {
             let (_temp_dir, repo_path) = create_jujutsu_test_repo();
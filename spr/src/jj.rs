@@ -6,8 +6,9 @@
  */
 
 use std::{
+    collections::HashMap,
     ffi::OsStr,
-    path::PathBuf,
+    path::{Path, PathBuf},
     process::{Command, Stdio},
 };
 
@@ -30,8 +31,67 @@ pub struct PreparedCommit {
 
 pub struct Jujutsu {
     repo_path: PathBuf,
-    jj_bin: PathBuf,
+    backend: Box<dyn JjBackend>,
+    git_executor: Box<dyn GitExecutor>,
     pub git_repo: git2::Repository,
+    // Whether `git_repo`'s `.git` directory sits directly in the jj
+    // workspace root (`jj git init --colocate`), as opposed to being jj's
+    // own private backing store (`jj git init`, no `--colocate`). A
+    // colocated repo's `.git` is kept in sync by jj on every `jj`
+    // invocation, so git2/raw-`git` calls against it always see up-to-date
+    // refs. A non-colocated repo's backing store is only ever touched by jj
+    // itself - anything we do to it outside of jj (a raw `git push`) is
+    // invisible to jj until we explicitly `jj git export`, and anything
+    // landed in it from outside (a `git fetch`) is invisible to jj until we
+    // `jj git import`.
+    colocated: bool,
+}
+
+/// See [`Jujutsu::begin_atomic_submit`].
+pub struct OperationGuard<'a> {
+    jj: &'a Jujutsu,
+    operation_id: String,
+    armed: bool,
+}
+
+impl OperationGuard<'_> {
+    /// Mark the submit as having completed successfully, so dropping the
+    /// guard no longer rolls back local jj state.
+    pub fn disarm(mut self) {
+        self.armed = false;
+    }
+}
+
+impl Drop for OperationGuard<'_> {
+    fn drop(&mut self) {
+        if self.armed {
+            if let Err(err) = self.jj.restore_operation(&self.operation_id) {
+                eprintln!(
+                    "[spr] failed to roll back to operation {}: {}",
+                    self.operation_id, err
+                );
+            }
+        }
+    }
+}
+
+/// Result of comparing a remote branch's current value against what we last
+/// recorded pushing there and what we're about to push next - a three-way
+/// comparison of (base, remote, ours) in the same spirit as the merge jj
+/// itself does when importing git refs that moved underneath it.
+#[derive(Debug, PartialEq, Eq)]
+pub enum RemoteBranchStatus {
+    /// The remote still has the value we last pushed (or, with no record of
+    /// a push, the fallback base the caller passed in), or the branch does
+    /// not exist on the remote yet - safe to push our new value.
+    UpToDate,
+    /// The remote already has the value we're about to push - nothing to
+    /// do.
+    AlreadyPushed,
+    /// The remote has moved to something other than our recorded base or
+    /// the value we want to push - a genuine concurrent change, not a
+    /// fast-forward. Pushing now would silently discard it.
+    Diverged { base: Oid, remote: Oid },
 }
 
 impl Jujutsu {
@@ -50,29 +110,127 @@ impl Jujutsu {
             ));
         }
 
-        let jj_bin = get_jj_bin();
+        let backend = Box::new(ShellJjBackend {
+            jj_bin: get_jj_bin(),
+            repo_path: repo_path.clone(),
+        });
+
+        // Colocated iff `.git` sits right in the workspace root, as opposed
+        // to jj's own backing store tucked away at
+        // `<root>/.jj/repo/store/git`.
+        let colocated = git_repo.path().ends_with(".git");
 
         Ok(Self {
             repo_path,
-            jj_bin,
+            backend,
+            git_executor: Box::new(ShellGitExecutor),
             git_repo,
+            colocated,
         })
     }
 
+    /// Make sure jj has noticed any refs that were written straight into the
+    /// backing git store outside of jj (e.g. a raw `git fetch`). A no-op for
+    /// colocated repos, which jj keeps in sync on its own.
+    fn sync_from_git(&self) -> Result<()> {
+        if !self.colocated {
+            self.run_captured_with_args(["git", "import"])
+                .reword("Failed to import git refs into jj".to_string())?;
+        }
+        Ok(())
+    }
+
+    /// Make sure jj's view of the repo has been written out to the backing
+    /// git store, so that a subsequent raw `git` call (e.g. our push) sees
+    /// it. A no-op for colocated repos, which jj keeps in sync on its own.
+    fn sync_to_git(&self) -> Result<()> {
+        if !self.colocated {
+            self.run_captured_with_args(["git", "export"])
+                .reword("Failed to export jj state to git".to_string())?;
+        }
+        Ok(())
+    }
+
     pub fn get_prepared_commit_for_revision(
         &self,
         config: &Config,
         revision: &str,
     ) -> Result<PreparedCommit> {
         let commit_oid = self.resolve_revision_to_commit_id(revision)?;
-        self.prepare_commit(config, commit_oid)
+        let commit = self.prepare_commit(config, commit_oid)?;
+        self.check_no_conflicts(std::slice::from_ref(&commit))?;
+        Ok(commit)
     }
 
     pub fn get_master_base_for_commit(&self, config: &Config, commit_oid: Oid) -> Result<Oid> {
         // Find the merge base between the commit and master
         let master_oid = self.resolve_revision_to_commit_id(config.master_ref.local())?;
-        let merge_base = self.git_repo.merge_base(commit_oid, master_oid)?;
-        Ok(merge_base)
+        self.merge_base(commit_oid, master_oid)
+    }
+
+    /// The (greatest) common ancestor of `a` and `b`, per jj's own view of
+    /// the graph. Returns an error if they have none.
+    pub fn merge_base(&self, a: Oid, b: Oid) -> Result<Oid> {
+        self.merge_base_many(&[a, b])?
+            .into_iter()
+            .next()
+            .ok_or_else(|| Error::new(format!("{} and {} have no common ancestor", a, b)))
+    }
+
+    /// Greatest common ancestors of `commits`, computed via jj's revset
+    /// engine (`heads(::c1 & ::c2 & ...)`) rather than git2's graph walk.
+    /// Unlike a `git2::Repository::merge_base` call, this stays correct
+    /// across operations that have rewritten commits, since it asks jj
+    /// rather than recomputing ancestry from the raw git object graph
+    /// ourselves - and gives every merge-base query in the crate one tested
+    /// code path to go through. Usually returns a single commit, but can
+    /// return more than one when the inputs have no single greatest common
+    /// ancestor.
+    pub fn merge_base_many(&self, commits: &[Oid]) -> Result<Vec<Oid>> {
+        if commits.len() < 2 {
+            return Err(Error::new(
+                "merge_base_many needs at least two commits".to_string(),
+            ));
+        }
+
+        let ancestors_of_all = commits
+            .iter()
+            .map(|commit| format!("::{}", commit))
+            .collect::<Vec<_>>()
+            .join(" & ");
+
+        let output = self.run_captured_with_args([
+            "log",
+            "--no-graph",
+            "-r",
+            &format!("heads({})", ancestors_of_all),
+            "--template",
+            r#"commit_id ++ "\n""#,
+        ])?;
+
+        let heads: Result<Vec<Oid>> = output
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(|line| {
+                Oid::from_str(line).map_err(|e| {
+                    Error::new(format!(
+                        "Failed to parse commit ID '{}' from jj output: {}",
+                        line, e
+                    ))
+                })
+            })
+            .collect();
+        let heads = heads?;
+
+        if heads.is_empty() {
+            return Err(Error::new(format!(
+                "{:?} have no common ancestor",
+                commits
+            )));
+        }
+
+        Ok(heads)
     }
 
     pub fn get_prepared_commits_from_to(
@@ -81,12 +239,31 @@ impl Jujutsu {
         from_revision: &str,
         to_revision: &str,
     ) -> Result<Vec<PreparedCommit>> {
-        // Get commit range using jj
+        self.get_prepared_commits_for_revset(
+            config,
+            &format!("{}::{}", from_revision, to_revision),
+        )
+    }
+
+    /// Prepare commits for an arbitrary jj revset expression, such as
+    /// `mine() & ~::main` or `trunk()..@`. This is handed straight to `jj
+    /// log`, so anything a user could type on the jj command line works
+    /// here, rather than forcing a linear from/to pair.
+    pub fn get_prepared_commits_for_revset(
+        &self,
+        config: &Config,
+        revset: &str,
+    ) -> Result<Vec<PreparedCommit>> {
+        // `jj log` defaults to newest-first; callers (base-branch detection,
+        // `--stack` chaining, message rewriting) all walk the result
+        // oldest-first assuming `commits[0]` is the bottom of the stack, so
+        // reverse the order jj gives us.
         let output = self.run_captured_with_args([
             "log",
             "--no-graph",
+            "--reversed",
             "-r",
-            &format!("{}::{}", from_revision, to_revision),
+            revset,
             "--template",
             "commit_id ++ \"\\n\"",
         ])?;
@@ -102,6 +279,8 @@ impl Jujutsu {
             }
         }
 
+        self.check_no_conflicts(&commits)?;
+
         Ok(commits)
     }
 
@@ -145,6 +324,194 @@ impl Jujutsu {
             .ok_or_else(|| Error::new(format!("Reference {} has no target", ref_name)))
     }
 
+    /// Path of the persistent push-lease map: for each branch we've pushed a
+    /// Pull Request (or its base) to, the remote commit SHA we observed
+    /// right after that push succeeded. This is spr's own memory of "what I
+    /// last left on the remote", independent of whatever GitHub's API
+    /// happens to report for the PR right now.
+    fn push_lease_map_path(&self) -> PathBuf {
+        self.repo_path.join(".jj").join("spr-push-lease")
+    }
+
+    fn load_push_lease_map(&self) -> Result<HashMap<String, Oid>> {
+        let path = self.push_lease_map_path();
+        if !path.exists() {
+            return Ok(HashMap::new());
+        }
+
+        let contents = std::fs::read_to_string(&path)
+            .context(format!("failed to read {}", path.display()))?;
+
+        let mut map = HashMap::new();
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            if let Some((branch_name, oid)) = line.split_once(' ') {
+                if let Ok(oid) = Oid::from_str(oid.trim()) {
+                    map.insert(branch_name.trim().to_string(), oid);
+                }
+            }
+        }
+
+        Ok(map)
+    }
+
+    fn save_push_lease_map(&self, map: &HashMap<String, Oid>) -> Result<()> {
+        let path = self.push_lease_map_path();
+        let mut contents = String::new();
+        for (branch_name, oid) in map {
+            contents.push_str(&format!("{} {}\n", branch_name, oid));
+        }
+
+        std::fs::write(&path, contents).context(format!("failed to write {}", path.display()))
+    }
+
+    /// Record that we just pushed `oid` to `remote_branch_name`, so the next
+    /// push to that branch can tell whether anyone else has moved it since.
+    pub fn record_push_lease(&self, remote_branch_name: &str, oid: Oid) -> Result<()> {
+        let mut map = self.load_push_lease_map()?;
+        map.insert(remote_branch_name.to_string(), oid);
+        self.save_push_lease_map(&map)
+    }
+
+    /// Push `refspecs` to `remote`, through this repo's [`GitExecutor`]
+    /// rather than git2 (whose push API has no equivalent of force-with-
+    /// lease). `leased_refs` names, for each ref we already validated via
+    /// [`check_remote_branch_status`], the branch name and the SHA we
+    /// expect it to still be at; for each of those we add git's own
+    /// `--force-with-lease=<ref>:<expected>`, so the remote rejects the
+    /// push atomically if it moved again in the narrow window between our
+    /// check and this push, instead of relying solely on our own
+    /// check-then-push.
+    ///
+    /// `force` is the user's `--force`/`--no-verify`-style escape hatch for
+    /// when they've already seen and want to override a divergence: it
+    /// drops the lease entirely in favor of a plain `--force`, since a
+    /// genuinely diverged remote would otherwise still reject a push that
+    /// carries a now-stale `--force-with-lease` expectation, silently
+    /// defeating the override.
+    pub fn push_with_lease(
+        &self,
+        remote: &str,
+        refspecs: &[String],
+        leased_refs: &[(String, Oid)],
+        force: bool,
+    ) -> Result<()> {
+        // This push shells out to `git` directly rather than going through
+        // jj, so a non-colocated repo's backing store needs to be brought
+        // up to date with jj's view first - otherwise it's still pushing
+        // whatever was there before the last `jj` operation.
+        self.sync_to_git()?;
+
+        let mut args = vec![
+            "push".to_string(),
+            "--atomic".to_string(),
+            "--no-verify".to_string(),
+        ];
+
+        if force {
+            args.push("--force".to_string());
+        } else {
+            for (branch_name, expected_oid) in leased_refs {
+                args.push(format!(
+                    "--force-with-lease={}:{}",
+                    branch_name, expected_oid
+                ));
+            }
+        }
+
+        args.push("--".to_string());
+        args.push(remote.to_string());
+        args.extend(refspecs.iter().cloned());
+
+        let outcome = self.git_executor.run(&args, &self.repo_path)?;
+        if !outcome.success {
+            return Err(Error::new(format!("git push failed: {}", outcome.stderr)));
+        }
+
+        // The push above may have updated local remote-tracking refs
+        // straight in the backing git store; make sure jj notices.
+        self.sync_from_git()?;
+
+        Ok(())
+    }
+
+    /// Compare a remote branch's current value against what we last left
+    /// there and what we're about to push, the way `git push
+    /// --force-with-lease` would before allowing a forced push - except we
+    /// report a three-way status rather than only erroring, so callers can
+    /// decide how to handle a genuine divergence instead of always
+    /// aborting.
+    ///
+    /// `git2`'s refspec push can't express force-with-lease directly, so we
+    /// ask the remote for the branch's current OID ourselves. The "base" we
+    /// compare against is the OID recorded in the local push-lease map from
+    /// the last time spr itself pushed this branch, falling back to
+    /// `fallback_base_oid` (e.g. the head of the Pull Request as last
+    /// fetched from GitHub) if we have no such record - typically because
+    /// this is the first push to this branch from this clone.
+    pub fn check_remote_branch_status(
+        &self,
+        remote: &str,
+        remote_branch_name: &str,
+        fallback_base_oid: Oid,
+        new_oid: Oid,
+    ) -> Result<RemoteBranchStatus> {
+        let base_oid = self
+            .load_push_lease_map()?
+            .get(remote_branch_name)
+            .copied()
+            .unwrap_or(fallback_base_oid);
+
+        let outcome = self.git_executor.run(
+            &[
+                "ls-remote".to_string(),
+                "--exit-code".to_string(),
+                remote.to_string(),
+                remote_branch_name.to_string(),
+            ],
+            &self.repo_path,
+        )?;
+
+        // Exit code 2 means the ref doesn't exist on the remote (yet), which
+        // is fine - there's nothing for us to clobber.
+        if outcome.exit_code == Some(2) {
+            return Ok(RemoteBranchStatus::UpToDate);
+        }
+
+        if !outcome.success {
+            return Err(Error::new(format!(
+                "Failed to check remote branch {}: {}",
+                remote_branch_name, outcome.stderr
+            )));
+        }
+
+        let current_oid = outcome
+            .stdout
+            .split_whitespace()
+            .next()
+            .and_then(|s| Oid::from_str(s).ok())
+            .ok_or_else(|| {
+                Error::new(format!(
+                    "Could not parse remote OID for {} from: {}",
+                    remote_branch_name, outcome.stdout
+                ))
+            })?;
+
+        if current_oid == base_oid {
+            Ok(RemoteBranchStatus::UpToDate)
+        } else if current_oid == new_oid {
+            Ok(RemoteBranchStatus::AlreadyPushed)
+        } else {
+            Ok(RemoteBranchStatus::Diverged {
+                base: base_oid,
+                remote: current_oid,
+            })
+        }
+    }
+
     pub fn get_tree_oid_for_commit(&self, commit_oid: Oid) -> Result<Oid> {
         let commit = self.git_repo.find_commit(commit_oid)?;
         Ok(commit.tree()?.id())
@@ -198,43 +565,183 @@ impl Jujutsu {
         Ok(index.write_tree_to(&self.git_repo)?)
     }
 
+    /// Replace every conflicting entry in `index` with a single staged blob
+    /// containing standard `<<<<<<<`/`=======`/`>>>>>>>` conflict markers, so
+    /// the index (and the tree built from it) no longer has conflicts and
+    /// can be committed - with the markers left in place for the author to
+    /// resolve, the way jj itself keeps a conflicted commit as a first-class
+    /// member of the graph instead of stopping to resolve it. Returns the
+    /// resolved index along with the paths that conflicted, so callers can
+    /// report them.
+    pub fn resolve_conflicts_with_markers(
+        &self,
+        mut index: git2::Index,
+    ) -> Result<(git2::Index, Vec<String>)> {
+        let conflicts: Vec<_> = index
+            .conflicts()?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+
+        let mut conflicted_paths = Vec::new();
+
+        for conflict in conflicts {
+            let ancestor = conflict.ancestor.as_ref();
+            let our = conflict.our.as_ref();
+            let their = conflict.their.as_ref();
+
+            let path = our
+                .or(their)
+                .or(ancestor)
+                .map(|e| e.path.clone())
+                .ok_or_else(|| Error::new("Conflict entry had no path".to_string()))?;
+            let mode = our.or(their).or(ancestor).map(|e| e.mode).unwrap_or(0o100644);
+            let path_str = String::from_utf8_lossy(&path).into_owned();
+
+            let mut merge_opts = git2::MergeFileOptions::new();
+            merge_opts.style_merge(true);
+            let merge_result =
+                self.git_repo
+                    .merge_file_from_index(ancestor, our, their, Some(&merge_opts))?;
+
+            let blob_oid = self.git_repo.blob(merge_result.content())?;
+
+            index.remove_path(Path::new(&path_str))?;
+            index.add(&git2::IndexEntry {
+                ctime: git2::IndexTime::new(0, 0),
+                mtime: git2::IndexTime::new(0, 0),
+                dev: 0,
+                ino: 0,
+                mode,
+                uid: 0,
+                gid: 0,
+                file_size: merge_result.content().len() as u32,
+                id: blob_oid,
+                flags: 0,
+                flags_extended: 0,
+                path,
+            })?;
+
+            conflicted_paths.push(path_str);
+        }
+
+        conflicted_paths.sort();
+        Ok((index, conflicted_paths))
+    }
+
+    /// The id of the jj operation that is current right now. Stashing this
+    /// before a multi-step submit lets us roll the repository's local state
+    /// back to exactly this point with a single `jj op restore` if the
+    /// submit fails partway through, so a failed `spr diff` doesn't leave
+    /// behind a partial rewrite that the user has to untangle by hand.
+    pub fn current_operation_id(&self) -> Result<String> {
+        let output =
+            self.run_captured_with_args(["op", "log", "--no-graph", "-n", "1", "-T", "id.short()"])?;
+        Ok(output.trim().to_string())
+    }
+
+    /// Restore the repository to the state it was in at `operation_id`. Used
+    /// to roll back local jj state (e.g. a batch of `jj describe` calls) if
+    /// a submit fails after making local changes but before they're all
+    /// safely pushed.
+    pub fn restore_operation(&self, operation_id: &str) -> Result<()> {
+        self.run_captured_with_args(["op", "restore", operation_id])?;
+        Ok(())
+    }
+
+    /// Start an atomic submit: everything this `Jujutsu` does until the
+    /// returned guard is disarmed is treated as one unit. If the guard is
+    /// dropped while still armed - because the caller returned early or
+    /// bailed out with `?` - it automatically restores the repository to the
+    /// operation recorded here, so a failed submit can't leave the local
+    /// repository in a half-rewritten state that the caller has to remember
+    /// to clean up.
+    pub fn begin_atomic_submit(&self) -> Result<OperationGuard<'_>> {
+        Ok(OperationGuard {
+            jj: self,
+            operation_id: self.current_operation_id()?,
+            armed: true,
+        })
+    }
+
+    /// Rewrite the description of every commit whose message changed.
+    ///
+    /// `jj describe` takes a single revset and a single set of `-m` values
+    /// and applies the *joined* message to *every* selected revision, so
+    /// `-r id1 -m msg1 -r id2 -m msg2` does not mean "msg1 for id1, msg2 for
+    /// id2" - it means "msg1\n\nmsg2 for both id1 and id2". We therefore
+    /// issue one `describe` per commit. To roll back cleanly if we fail
+    /// partway through, the whole loop runs under one [`OperationGuard`].
+    /// Each `describe` is still its own jj operation, so a successful
+    /// rewrite of N commits takes N `jj op undo`s (or one `jj op restore`
+    /// to the operation before it) to revert.
     pub fn rewrite_commit_messages(&self, commits: &mut [PreparedCommit]) -> Result<()> {
         if commits.is_empty() {
             return Ok(());
         }
 
-        // Use jj describe to update commit messages, but only for commits that actually changed
-        for prepared_commit in commits.iter_mut() {
-            // Only update commits whose messages were actually modified
-            if !prepared_commit.message_changed {
+        let guard = self.begin_atomic_submit()?;
+
+        for index in 0..commits.len() {
+            if !commits[index].message_changed {
                 continue;
             }
 
-            let new_message = build_commit_message(&prepared_commit.message);
+            let new_message = build_commit_message(&commits[index].message);
+            let change_id = self.get_change_id_for_commit(commits[index].oid)?;
+
+            self.run_captured_with_args(["describe", "-r", &change_id, "-m", &new_message])
+                .reword("Failed to update commit messages".to_string())?;
+
+            commits[index].message_changed = false;
+        }
 
-            // Get the change ID for this commit
-            let change_id = self.get_change_id_for_commit(prepared_commit.oid)?;
+        // The `jj describe` calls above rewrote commits; push that out to
+        // the backing git store so a non-colocated repo's subsequent raw
+        // `git`/git2 calls (e.g. the push in `diff_impl`) see the new
+        // commit IDs.
+        self.sync_to_git()?;
 
-            // Update the commit message using jj describe
-            let mut cmd = Command::new(&self.jj_bin);
-            cmd.args(["describe", "-r", &change_id, "-m", &new_message])
-                .current_dir(&self.repo_path)
-                .stdout(Stdio::piped())
-                .stderr(Stdio::piped());
+        guard.disarm();
 
-            let output = cmd.output()?;
-            if !output.status.success() {
-                return Err(Error::new(format!(
-                    "Failed to update commit message: {}",
-                    String::from_utf8_lossy(&output.stderr)
-                )));
+        Ok(())
+    }
+
+    /// jj stores conflicted commits as first-class members of the commit
+    /// graph, materialized with conflict markers in the tree. Submitting one
+    /// would push those conflict markers straight to GitHub, so we check for
+    /// this and fail with an actionable message before any branch is
+    /// touched.
+    fn check_no_conflicts(&self, commits: &[PreparedCommit]) -> Result<()> {
+        let mut conflicted_change_ids = Vec::new();
+
+        for commit in commits {
+            let change_id = self.get_change_id_for_commit(commit.oid)?;
+            if self.commit_has_conflict(&change_id)? {
+                conflicted_change_ids.push(change_id);
             }
+        }
 
-            // Reset the flag after successful update
-            prepared_commit.message_changed = false;
+        if conflicted_change_ids.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::new(format!(
+                "The following commits have unresolved conflicts and cannot be submitted:\n  {}\n\
+                 Resolve the conflicts (e.g. with `jj resolve`) before running spr again.",
+                conflicted_change_ids.join("\n  ")
+            )))
         }
+    }
 
-        Ok(())
+    fn commit_has_conflict(&self, change_id: &str) -> Result<bool> {
+        let output = self.run_captured_with_args([
+            "log",
+            "--no-graph",
+            "-r",
+            change_id,
+            "--template",
+            "if(conflict, \"conflict\")",
+        ])?;
+
+        Ok(output.trim() == "conflict")
     }
 
     fn prepare_commit(&self, config: &Config, commit_oid: Oid) -> Result<PreparedCommit> {
@@ -251,9 +758,24 @@ impl Jujutsu {
         let message_text = commit.message().unwrap_or("").to_string();
         let message = parse_message(&message_text, MessageSection::Title);
 
-        let pull_request_number = message
-            .get(&MessageSection::PullRequest)
-            .and_then(|url| config.parse_pull_request_field(url));
+        // The change-id -> PR mapping survives `jj rebase`/`jj squash`/
+        // description edits that change this commit's OID, so prefer it over
+        // the message, which may not have been rewritten yet. Fall back to
+        // the content hash (which survives even a `jj duplicate`, that gets
+        // a fresh change-id but carries over the same diff), and finally to
+        // whatever's recorded in the message itself.
+        let change_id = self.get_change_id_for_commit(commit_oid)?;
+        let pull_request_number = self
+            .get_pull_request_for_change_id(&change_id)?
+            .or(match self.get_content_hash_for_commit(commit_oid) {
+                Ok(hash) => self.get_pull_request_for_content_hash(hash)?,
+                Err(_) => None,
+            })
+            .or_else(|| {
+                message
+                    .get(&MessageSection::PullRequest)
+                    .and_then(|url| config.parse_pull_request_field(url))
+            });
 
         Ok(PreparedCommit {
             oid: commit_oid,
@@ -265,6 +787,46 @@ impl Jujutsu {
         })
     }
 
+    /// Resolve an arbitrary jj revset expression to exactly one commit, for
+    /// options like `--base` that name a single base point rather than a
+    /// range - `@-`, `main..@` (as a boundary), `description(glob:"WIP*")`,
+    /// `heads(...)`, ancestor offsets like `base-1`, and so on. Unlike
+    /// `resolve_revision_to_commit_id`, which just takes whatever jj prints
+    /// and lets a bad parse surface as a generic Oid error, this explicitly
+    /// checks how many commits the expression matched, so a revset that's
+    /// empty or ambiguous gets a message that says so instead of an opaque
+    /// parse failure.
+    pub fn resolve_single_revision(&self, revision: &str) -> Result<Oid> {
+        let output = self.run_captured_with_args([
+            "log",
+            "--no-graph",
+            "-r",
+            revision,
+            "--template",
+            r#"commit_id ++ "\n""#,
+        ])?;
+
+        let mut commit_ids = output.lines().map(str::trim).filter(|line| !line.is_empty());
+
+        let first = commit_ids.next().ok_or_else(|| {
+            Error::new(format!("'{}' did not resolve to any commit", revision))
+        })?;
+
+        if commit_ids.next().is_some() {
+            return Err(Error::new(format!(
+                "'{}' resolved to more than one commit - expected exactly one",
+                revision
+            )));
+        }
+
+        Oid::from_str(first).map_err(|e| {
+            Error::new(format!(
+                "Failed to parse commit ID '{}' from jj output: {}",
+                first, e
+            ))
+        })
+    }
+
     fn resolve_revision_to_commit_id(&self, revision: &str) -> Result<Oid> {
         let output = self.run_captured_with_args([
             "log",
@@ -284,7 +846,154 @@ impl Jujutsu {
         })
     }
 
-    fn get_change_id_for_commit(&self, commit_oid: Oid) -> Result<String> {
+    /// Path of the persistent change-id -> pull-request-number mapping. It
+    /// lives under `.jj` rather than in jj's repo config, since it's spr's
+    /// own bookkeeping rather than something a jj user would want to edit.
+    fn change_id_pr_map_path(&self) -> PathBuf {
+        self.repo_path.join(".jj").join("spr-pr-map")
+    }
+
+    fn load_change_id_pr_map(&self) -> Result<HashMap<String, u64>> {
+        let path = self.change_id_pr_map_path();
+        if !path.exists() {
+            return Ok(HashMap::new());
+        }
+
+        let contents = std::fs::read_to_string(&path)
+            .context(format!("failed to read {}", path.display()))?;
+
+        let mut map = HashMap::new();
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            if let Some((change_id, pr_number)) = line.split_once(' ') {
+                if let Ok(pr_number) = pr_number.trim().parse::<u64>() {
+                    map.insert(change_id.trim().to_string(), pr_number);
+                }
+            }
+        }
+
+        Ok(map)
+    }
+
+    fn save_change_id_pr_map(&self, map: &HashMap<String, u64>) -> Result<()> {
+        let path = self.change_id_pr_map_path();
+        let mut contents = String::new();
+        for (change_id, pr_number) in map {
+            contents.push_str(&format!("{} {}\n", change_id, pr_number));
+        }
+
+        std::fs::write(&path, contents).context(format!("failed to write {}", path.display()))
+    }
+
+    /// Look up the pull request number associated with a jj change-id, if
+    /// spr has previously recorded one.
+    pub fn get_pull_request_for_change_id(&self, change_id: &str) -> Result<Option<u64>> {
+        Ok(self.load_change_id_pr_map()?.get(change_id).copied())
+    }
+
+    /// Record that `change_id` is associated with `pull_request_number`, so
+    /// the link survives future rebases/amends that change the commit's OID
+    /// but not its change-id.
+    pub fn record_pull_request_for_change_id(
+        &self,
+        change_id: &str,
+        pull_request_number: u64,
+    ) -> Result<()> {
+        let mut map = self.load_change_id_pr_map()?;
+        map.insert(change_id.to_string(), pull_request_number);
+        self.save_change_id_pr_map(&map)
+    }
+
+    /// Derive a stable content hash for a commit's change, based on the diff
+    /// between it and its parent. Unlike the change-id, this survives
+    /// operations that assign a brand new change-id but carry over the same
+    /// diff (e.g. `jj duplicate`), since it's keyed on content rather than
+    /// jj's own bookkeeping.
+    pub fn get_content_hash_for_commit(&self, commit_oid: Oid) -> Result<Oid> {
+        let commit = self.git_repo.find_commit(commit_oid)?;
+        let tree = commit.tree()?;
+        let parent_tree = if commit.parents().count() > 0 {
+            Some(commit.parent(0)?.tree()?)
+        } else {
+            None
+        };
+
+        let diff = self
+            .git_repo
+            .diff_tree_to_tree(parent_tree.as_ref(), Some(&tree), None)?;
+
+        let mut patch_bytes = Vec::new();
+        diff.print(git2::DiffFormat::Patch, |_delta, _hunk, line| {
+            patch_bytes.extend_from_slice(line.content());
+            true
+        })?;
+
+        Ok(self
+            .git_repo
+            .odb()?
+            .hash(&patch_bytes, git2::ObjectType::Blob)?)
+    }
+
+    /// The repo-relative paths touched by `commit_oid`, relative to its
+    /// first parent (or the empty tree, for a root commit). Used to match
+    /// changed files against CODEOWNERS when auto-assigning reviewers.
+    pub fn get_changed_paths_for_commit(&self, commit_oid: Oid) -> Result<Vec<String>> {
+        let commit = self.git_repo.find_commit(commit_oid)?;
+        let tree = commit.tree()?;
+        let parent_tree = if commit.parents().count() > 0 {
+            Some(commit.parent(0)?.tree()?)
+        } else {
+            None
+        };
+
+        let diff = self
+            .git_repo
+            .diff_tree_to_tree(parent_tree.as_ref(), Some(&tree), None)?;
+
+        let mut paths = Vec::new();
+        for delta in diff.deltas() {
+            for file in [delta.old_file(), delta.new_file()] {
+                if let Some(path) = file.path().and_then(|p| p.to_str()) {
+                    paths.push(path.to_string());
+                }
+            }
+        }
+        paths.sort();
+        paths.dedup();
+
+        Ok(paths)
+    }
+
+    fn content_hash_map_key(hash: Oid) -> String {
+        format!("hash:{}", hash)
+    }
+
+    /// Look up the pull request number associated with a content hash, if
+    /// spr has previously recorded one.
+    pub fn get_pull_request_for_content_hash(&self, hash: Oid) -> Result<Option<u64>> {
+        Ok(self
+            .load_change_id_pr_map()?
+            .get(&Self::content_hash_map_key(hash))
+            .copied())
+    }
+
+    /// Record that the change with content hash `hash` is associated with
+    /// `pull_request_number`, as a fallback for when the change-id changes
+    /// but the content doesn't.
+    pub fn record_pull_request_for_content_hash(
+        &self,
+        hash: Oid,
+        pull_request_number: u64,
+    ) -> Result<()> {
+        let mut map = self.load_change_id_pr_map()?;
+        map.insert(Self::content_hash_map_key(hash), pull_request_number);
+        self.save_change_id_pr_map(&map)
+    }
+
+    pub fn get_change_id_for_commit(&self, commit_oid: Oid) -> Result<String> {
         // Get the change ID for a given commit OID
         let output = self.run_captured_with_args([
             "log",
@@ -298,15 +1007,77 @@ impl Jujutsu {
         Ok(output.trim().to_string())
     }
 
+    /// Create or move a jj bookmark so it points at `change_id`, the way
+    /// `git branch -f` would. This is the tracking pointer tying a submitted
+    /// PR back to the user's own change (as opposed to the synthetic
+    /// derived commit we push to GitHub as the Pull Request branch), so
+    /// `jj log`/`jj bookmark list` show which of the user's changes are
+    /// published and let the user navigate their open PRs without resorting
+    /// to `git branch -r` or re-querying GitHub.
+    ///
+    /// Moving a bookmark to a commit that isn't a descendant of where it
+    /// currently points (e.g. after a history-rewriting `spr diff`) is
+    /// exactly what `--allow-backwards` is for.
+    pub fn set_bookmark(&self, name: &str, change_id: &str) -> Result<()> {
+        self.run_captured_with_args([
+            "bookmark",
+            "set",
+            "--allow-backwards",
+            name,
+            "-r",
+            change_id,
+        ])
+        .reword(format!("Failed to set jj bookmark '{}'", name))?;
+        Ok(())
+    }
+
     fn run_captured_with_args<I, S>(&self, args: I) -> Result<String>
     where
         I: IntoIterator<Item = S>,
         S: AsRef<OsStr>,
     {
+        let args: Vec<String> = args
+            .into_iter()
+            .map(|arg| arg.as_ref().to_string_lossy().into_owned())
+            .collect();
+        self.backend.run(&args)
+    }
+}
+
+/// Abstraction over how spr talks to jj, so the rest of this file doesn't
+/// care whether that's by shelling out to the `jj` binary or (eventually)
+/// an in-process library call.
+///
+/// We don't implement the latter today: jj-lib, the crate backing the `jj`
+/// CLI, does not have a stable public API and its maintainers are explicit
+/// that the `jj` binary is the only supported integration surface - a given
+/// jj-lib version is paired with a given `jj` release, with no semver
+/// compatibility promise in between. Shelling out to `jj` is therefore not a
+/// stopgap so much as the only integration surface jj currently offers; this
+/// trait exists so that can change without touching every call site here.
+///
+/// In other words, this is only a seam, not a backend: today there is
+/// exactly one implementation ([`ShellJjBackend`]), and adopting jj-lib is
+/// future work, not something already delivered here. Don't expect the
+/// performance or non-UTF-8-output wins an in-process backend would bring
+/// until that implementation actually exists.
+trait JjBackend: std::fmt::Debug {
+    fn run(&self, args: &[String]) -> Result<String>;
+}
+
+#[derive(Debug)]
+struct ShellJjBackend {
+    jj_bin: PathBuf,
+    repo_path: PathBuf,
+}
+
+impl JjBackend for ShellJjBackend {
+    fn run(&self, args: &[String]) -> Result<String> {
         let mut command = Command::new(&self.jj_bin);
         command.args(args);
         command.current_dir(&self.repo_path);
         command.stdout(Stdio::piped());
+        command.stderr(Stdio::piped());
 
         let child = command.spawn().context("jj failed to spawn".to_string())?;
         let output = child
@@ -314,22 +1085,99 @@ impl Jujutsu {
             .context("failed to wait for jj to exit".to_string())?;
 
         if output.status.success() {
-            let output = String::from_utf8(output.stdout)
-                .context("jujutsu output was not valid UTF-8".to_string())?;
-            Ok(output)
+            String::from_utf8(output.stdout).context("jujutsu output was not valid UTF-8".to_string())
         } else {
-            Err(Error::new(format!(
-                "jujutsu exited with code {}, stderr:\n{}",
-                output
-                    .status
-                    .code()
-                    .map_or_else(|| "(unknown)".to_string(), |c| c.to_string()),
-                String::from_utf8_lossy(&output.stderr)
-            )))
+            Err(jj_error(output.status.code(), &output.stderr))
         }
     }
 }
 
+/// Output of a single `git` invocation run through a [`GitExecutor`]:
+/// whether it succeeded, its exit code (some callers, like `git ls-remote
+/// --exit-code`, give specific meaning to particular non-zero codes), and
+/// its captured stdout/stderr - independent of however the executor
+/// actually ran it.
+#[derive(Debug, Clone)]
+struct GitCommandOutcome {
+    success: bool,
+    exit_code: Option<i32>,
+    stdout: String,
+    stderr: String,
+}
+
+/// Runs `git` commands that jj-spr needs but that git2-rs can't express -
+/// `--force-with-lease=<ref>:<expected>` push refspecs, in particular, which
+/// have no equivalent in git2's push API. Broken out behind a trait, the
+/// same way [`JjBackend`] sits between `Jujutsu` and the `jj` binary, so a
+/// test can swap in a fake executor instead of always shelling out to a
+/// real `git`.
+trait GitExecutor: std::fmt::Debug {
+    fn run(&self, args: &[String], cwd: &Path) -> Result<GitCommandOutcome>;
+}
+
+#[derive(Debug)]
+struct ShellGitExecutor;
+
+impl GitExecutor for ShellGitExecutor {
+    fn run(&self, args: &[String], cwd: &Path) -> Result<GitCommandOutcome> {
+        let output = Command::new("git")
+            .args(args)
+            .current_dir(cwd)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .output()
+            .context("git failed to spawn".to_string())?;
+
+        Ok(GitCommandOutcome {
+            success: output.status.success(),
+            exit_code: output.status.code(),
+            stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        })
+    }
+}
+
+/// Turn a failed jj invocation's stderr into a [`Error`].
+///
+/// jj's own CLI errors are already structured - a leading `Error: ...` line,
+/// sometimes followed by `Caused by: ...` lines, sometimes followed by a
+/// `Hint: ...` suggestion - and jj colors that structure when it thinks
+/// it's writing to a terminal. We always pipe jj's stderr, so it never sees
+/// a terminal and never colors anything; here we parse that same structure
+/// back out of the plain text and reapply color ourselves, so a `jj`
+/// failure surfaced through spr reads the way it would running `jj`
+/// directly, no matter how many layers of `spr` machinery it passed through
+/// on the way up.
+fn jj_error(exit_code: Option<i32>, stderr: &[u8]) -> Error {
+    const RED: &str = "\x1b[31m";
+    const YELLOW: &str = "\x1b[33m";
+    const RESET: &str = "\x1b[0m";
+
+    let stderr = String::from_utf8_lossy(stderr);
+    let trimmed = stderr.trim();
+    let status = exit_code.map_or_else(|| "an unknown status".to_string(), |c| format!("code {}", c));
+
+    if trimmed.is_empty() {
+        return Error::new(format!("jj exited with {} and no output on stderr", status));
+    }
+
+    let formatted = trimmed
+        .lines()
+        .map(|line| {
+            if let Some(rest) = line.strip_prefix("Error: ") {
+                format!("{RED}Error:{RESET} {rest}")
+            } else if let Some(rest) = line.strip_prefix("Hint: ") {
+                format!("{YELLOW}Hint:{RESET} {rest}")
+            } else {
+                line.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    Error::new(format!("jj exited with {}:\n{}", status, formatted))
+}
+
 fn get_jj_bin() -> PathBuf {
     std::env::var_os("JJ").map_or_else(|| "jj".into(), |v| v.into())
 }
@@ -482,6 +1330,175 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_no_conflict_on_clean_commit() {
+        let (_temp_dir, repo_path) = create_jujutsu_test_repo();
+        let config = create_test_config();
+
+        let _commit = create_jujutsu_commit(&repo_path, "Clean commit", "content1");
+
+        let git_repo = git2::Repository::open(&repo_path).expect("Failed to open git repository");
+        let jj = Jujutsu::new(git_repo).expect("Failed to create Jujutsu instance");
+
+        let result = jj.get_prepared_commit_for_revision(&config, "@-");
+        assert!(
+            result.is_ok(),
+            "A conflict-free commit should not be rejected: {:?}",
+            result.err()
+        );
+    }
+
+    #[test]
+    fn test_pull_request_tracked_by_change_id() {
+        let (_temp_dir, repo_path) = create_jujutsu_test_repo();
+
+        let git_repo = git2::Repository::open(&repo_path).expect("Failed to open git repository");
+        let jj = Jujutsu::new(git_repo).expect("Failed to create Jujutsu instance");
+
+        let change_id = create_jujutsu_commit(&repo_path, "Some commit", "content1");
+
+        assert_eq!(
+            jj.get_pull_request_for_change_id(&change_id)
+                .expect("lookup should succeed"),
+            None
+        );
+
+        jj.record_pull_request_for_change_id(&change_id, 42)
+            .expect("record should succeed");
+
+        assert_eq!(
+            jj.get_pull_request_for_change_id(&change_id)
+                .expect("lookup should succeed"),
+            Some(42)
+        );
+    }
+
+    #[test]
+    fn test_pull_request_tracked_by_content_hash() {
+        let (_temp_dir, repo_path) = create_jujutsu_test_repo();
+
+        let git_repo = git2::Repository::open(&repo_path).expect("Failed to open git repository");
+        let jj = Jujutsu::new(git_repo).expect("Failed to create Jujutsu instance");
+
+        let _change_id = create_jujutsu_commit(&repo_path, "Some commit", "content1");
+        let commit_oid = jj
+            .resolve_revision_to_commit_id("@-")
+            .expect("should resolve @-");
+
+        let hash = jj
+            .get_content_hash_for_commit(commit_oid)
+            .expect("should hash content");
+
+        assert_eq!(
+            jj.get_pull_request_for_content_hash(hash)
+                .expect("lookup should succeed"),
+            None
+        );
+
+        jj.record_pull_request_for_content_hash(hash, 7)
+            .expect("record should succeed");
+
+        assert_eq!(
+            jj.get_pull_request_for_content_hash(hash)
+                .expect("lookup should succeed"),
+            Some(7)
+        );
+    }
+
+    #[test]
+    fn test_commits_for_revset() {
+        let (_temp_dir, repo_path) = create_jujutsu_test_repo();
+        let config = create_test_config();
+
+        // Create multiple commits
+        let _commit1 = create_jujutsu_commit(&repo_path, "First commit", "content1");
+        let _commit2 = create_jujutsu_commit(&repo_path, "Second commit", "content2");
+        let _commit3 = create_jujutsu_commit(&repo_path, "Third commit", "content3");
+
+        let git_repo = git2::Repository::open(&repo_path).expect("Failed to open git repository");
+        let jj = Jujutsu::new(git_repo).expect("Failed to create Jujutsu instance");
+
+        // An arbitrary revset expression, not just a linear from/to pair
+        let result = jj.get_prepared_commits_for_revset(&config, "@--..@");
+        assert!(
+            result.is_ok(),
+            "Failed to get commits for revset: {:?}",
+            result.err()
+        );
+
+        if let Ok(commits) = result {
+            assert!(!commits.is_empty(), "Should get some commits for revset");
+        }
+    }
+
+    #[test]
+    fn test_commits_for_revset_are_oldest_first() {
+        let (_temp_dir, repo_path) = create_jujutsu_test_repo();
+        let config = create_test_config();
+
+        // `jj log` defaults to newest-first; callers (e.g. `--stack` base
+        // detection in `spr diff`) assume `commits[0]` is the bottom of the
+        // stack, so `get_prepared_commits_for_revset` must reverse it.
+        let _commit1 = create_jujutsu_commit(&repo_path, "First commit", "content1");
+        let _commit2 = create_jujutsu_commit(&repo_path, "Second commit", "content2");
+        let _commit3 = create_jujutsu_commit(&repo_path, "Third commit", "content3");
+
+        let git_repo = git2::Repository::open(&repo_path).expect("Failed to open git repository");
+        let jj = Jujutsu::new(git_repo).expect("Failed to create Jujutsu instance");
+
+        let commits = jj
+            .get_prepared_commits_for_revset(&config, "@---..@")
+            .expect("Failed to get commits for revset");
+
+        assert!(commits.len() >= 2, "Should get more than one commit");
+        // Each commit's parent should be the previous entry's oid - i.e. the
+        // stack reads bottom-to-top, not newest-first.
+        for window in commits.windows(2) {
+            assert_eq!(
+                window[1].parent_oid, window[0].oid,
+                "commits should be ordered oldest-first"
+            );
+        }
+    }
+
+    #[test]
+    fn test_merge_base_finds_common_ancestor() {
+        let (_temp_dir, repo_path) = create_jujutsu_test_repo();
+
+        let _commit1 = create_jujutsu_commit(&repo_path, "First commit", "content1");
+        let _commit2 = create_jujutsu_commit(&repo_path, "Second commit", "content2");
+
+        let git_repo = git2::Repository::open(&repo_path).expect("Failed to open git repository");
+        let jj = Jujutsu::new(git_repo).expect("Failed to create Jujutsu instance");
+
+        let commit1_oid = jj
+            .resolve_revision_to_commit_id("@--")
+            .expect("should resolve @--");
+        let commit2_oid = jj
+            .resolve_revision_to_commit_id("@-")
+            .expect("should resolve @-");
+
+        // commit1 is an ancestor of commit2, so it's its own merge base with it
+        let merge_base = jj
+            .merge_base(commit1_oid, commit2_oid)
+            .expect("should find a merge base");
+        assert_eq!(merge_base, commit1_oid);
+    }
+
+    #[test]
+    fn test_merge_base_many_rejects_fewer_than_two_commits() {
+        let (_temp_dir, repo_path) = create_jujutsu_test_repo();
+
+        let git_repo = git2::Repository::open(&repo_path).expect("Failed to open git repository");
+        let jj = Jujutsu::new(git_repo).expect("Failed to create Jujutsu instance");
+
+        let commit_oid = jj
+            .resolve_revision_to_commit_id("@-")
+            .expect("should resolve @-");
+
+        assert!(jj.merge_base_many(&[commit_oid]).is_err());
+    }
+
     #[test]
     fn test_status_check() {
         let (_temp_dir, repo_path) = create_jujutsu_test_repo();
@@ -497,4 +1514,21 @@ mod tests {
             result.err()
         );
     }
+
+    #[test]
+    fn test_jj_error_colorizes_error_and_hint_lines() {
+        let error = jj_error(
+            Some(1),
+            b"Error: No such revision 'foo'\nHint: Did you mean 'bar'?",
+        );
+        let message = error.to_string();
+        assert!(message.contains("\x1b[31mError:\x1b[0m No such revision 'foo'"));
+        assert!(message.contains("\x1b[33mHint:\x1b[0m Did you mean 'bar'?"));
+    }
+
+    #[test]
+    fn test_jj_error_handles_empty_stderr() {
+        let error = jj_error(Some(1), b"");
+        assert!(error.to_string().contains("no output on stderr"));
+    }
 }